@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "std")]
+use crate::Error;
+
+/// Marks a compressed archive, followed by a byte identifying the
+/// compression; this must match what `wasm-bundle` writes.
+pub(crate) const MAGIC: &[u8] = b"\0wbundle";
+
+#[cfg(any(feature = "gzip", feature = "zstd", feature = "lz4"))]
+fn read_all(mut reader: impl std::io::Read) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).or(Err(Error::Malformed))?;
+    Ok(data)
+}
+
+/// Decompresses `data` if it is a compressed archive, returning `None`
+/// if it is a plain one.
+#[cfg(feature = "std")]
+pub(crate) fn decode(data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let rest = match data.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let (&id, compressed) = rest.split_first().ok_or(Error::Malformed)?;
+
+    let archive = match id {
+        0 => compressed.to_vec(),
+        #[cfg(feature = "gzip")]
+        1 => read_all(flate2::read::GzDecoder::new(compressed))?,
+        #[cfg(feature = "zstd")]
+        2 => read_all(
+            ruzstd::decoding::StreamingDecoder::new(compressed).or(Err(Error::Malformed))?,
+        )?,
+        #[cfg(feature = "lz4")]
+        3 => read_all(lz4_flex::frame::FrameDecoder::new(compressed))?,
+        _ => return Err(Error::Unsupported),
+    };
+
+    Ok(Some(archive))
+}
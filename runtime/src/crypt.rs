@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "decrypt")]
+use crate::Error;
+#[cfg(feature = "decrypt")]
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+/// Marks an encrypted archive, followed by a byte identifying the
+/// cipher, the ID of the key and the nonce; this must match what
+/// `wasm-bundle` writes.
+pub(crate) const MAGIC: &[u8] = b"\0wbcrypt";
+#[cfg(feature = "decrypt")]
+const KEY_ID_SIZE: usize = 8;
+#[cfg(feature = "decrypt")]
+const NONCE_SIZE: usize = 12;
+#[cfg(feature = "decrypt")]
+const HEADER_SIZE: usize = MAGIC.len() + 1 + KEY_ID_SIZE + NONCE_SIZE;
+
+/// Decrypts `data` with `key` if it is an encrypted archive, returning
+/// `None` if it is not.
+///
+/// The key ID isn't checked: a different key fails to authenticate the
+/// archive all the same.
+#[cfg(feature = "decrypt")]
+pub(crate) fn decrypt(data: &[u8], key: &[u8; 32]) -> Result<Option<Vec<u8>>, Error> {
+    if !data.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    if data.len() < HEADER_SIZE {
+        return Err(Error::Malformed);
+    }
+
+    let (header, payload) = data.split_at(HEADER_SIZE);
+    let nonce = aes_gcm::Nonce::from_slice(&header[HEADER_SIZE - NONCE_SIZE..]);
+    let payload = Payload {
+        msg: payload,
+        aad: header,
+    };
+    let result = match header[MAGIC.len()] {
+        1 => aes_gcm::Aes256Gcm::new(key.into()).decrypt(nonce, payload),
+        2 => chacha20poly1305::ChaCha20Poly1305::new(key.into()).decrypt(nonce, payload),
+        _ => return Err(Error::Unsupported),
+    };
+
+    result.map(Some).or(Err(Error::Decrypt))
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::Error;
+
+/// The SHA-256 digests of the bundled files, as recorded in the
+/// companion `.digest` section when bundling with `--digests`.
+///
+/// The guest hashes the contents returned by
+/// [`Resources::open`](crate::Resources::open) itself and compares them
+/// with [`Digests::get`], so only the files it reads are checked.
+pub struct Digests<T> {
+    data: T,
+}
+
+fn hex(digit: u8) -> Result<u8, Error> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        _ => Err(Error::Malformed),
+    }
+}
+
+/// Returns whether the escaped file name `name` is `path`.
+fn matches_escaped(name: &[u8], path: &[u8]) -> Result<bool, Error> {
+    let mut name = name.iter();
+    let mut path = path.iter();
+
+    while let Some(&byte) = name.next() {
+        let byte = match byte {
+            b'\\' => match name.next() {
+                Some(b'\\') => b'\\',
+                Some(b'n') => b'\n',
+                _ => return Err(Error::Malformed),
+            },
+            byte => byte,
+        };
+        if path.next() != Some(&byte) {
+            return Ok(false);
+        }
+    }
+
+    Ok(path.next().is_none())
+}
+
+impl<T: AsRef<[u8]>> Digests<T> {
+    /// Wraps the contents of the digest section, as provided by the
+    /// host.
+    pub fn new(data: T) -> Self {
+        Self { data }
+    }
+
+    /// Returns the digest recorded for the file stored at `path`,
+    /// which is matched as is, apart from a leading `/`.
+    pub fn get(&self, path: &str) -> Result<[u8; 32], Error> {
+        let path = path.trim_start_matches('/').as_bytes();
+
+        for line in self.data.as_ref().split(|byte| *byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            // Names with a backslash or newline are escaped, as
+            // sha256sum does, and the line flagged with a backslash.
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            if line.len() < 66 || &line[64..66] != b"  " {
+                return Err(Error::Malformed);
+            }
+            let found = if escaped {
+                matches_escaped(&line[66..], path)?
+            } else {
+                &line[66..] == path
+            };
+            if !found {
+                continue;
+            }
+
+            let mut digest = [0; 32];
+            for (i, byte) in digest.iter_mut().enumerate() {
+                *byte = hex(line[i * 2])? << 4 | hex(line[i * 2 + 1])?;
+            }
+            return Ok(digest);
+        }
+
+        Err(Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881";
+
+    #[test]
+    fn get_escaped() {
+        let mut data = [0; 256];
+        let mut len = 0;
+        for line in &[
+            "\\",
+            HEX,
+            "  back\\\\slash\n",
+            "\\",
+            HEX,
+            "  new\\nline\n",
+            HEX,
+            "  plain\n",
+        ] {
+            data[len..len + line.len()].copy_from_slice(line.as_bytes());
+            len += line.len();
+        }
+        let digests = Digests::new(&data[..len]);
+
+        let digest = digests.get("plain").unwrap();
+        assert_eq!(digest[..2], [0x2d, 0x71]);
+        assert_eq!(digests.get("/new\nline"), Ok(digest));
+        assert_eq!(digests.get("back\\slash"), Ok(digest));
+        assert_eq!(digests.get("new\\nline"), Err(Error::NotFound));
+        assert_eq!(digests.get("new"), Err(Error::NotFound));
+    }
+}
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read resource files bundled by `wasm-bundle` from inside a
+//! WebAssembly module.
+//!
+//! The bundled files are a tar archive stored in a custom section,
+//! which the guest cannot address directly.  The host makes the
+//! archive available either as a buffer handed to the guest, or as a
+//! file in a preopened directory, and [`Resources`] looks files up in
+//! it without copying.  Compressed archives are decompressed when
+//! loaded, if the matching `gzip`, `zstd` or `lz4` feature is enabled,
+//! and encrypted ones decrypted with [`Resources::decrypt`] given the
+//! provisioned key, if the `decrypt` feature is:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use std::io::Read;
+//!
+//! let resources = wasm_bundle_runtime::Resources::from_path("/resources.tar")?;
+//! let mut config = String::new();
+//! resources.open("config.toml").unwrap().read_to_string(&mut config)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+mod codec;
+mod crypt;
+mod digest;
+mod path;
+mod tar;
+
+pub use digest::Digests;
+use path::{split_first, PathBuf};
+
+use core::fmt;
+
+/// Errors returned while looking up resources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No entry with the given path exists.
+    NotFound,
+    /// The entry exists but is not a regular file.
+    NotAFile,
+    /// The archive is corrupt.
+    Malformed,
+    /// The archive is compressed with an unsupported codec, or
+    /// encrypted with an unsupported cipher.
+    Unsupported,
+    /// The archive couldn't be decrypted with the given key.
+    Decrypt,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "resource not found"),
+            Error::NotAFile => write!(f, "resource is not a regular file"),
+            Error::Malformed => write!(f, "malformed resource archive"),
+            Error::Unsupported => write!(f, "unsupported resource archive compression"),
+            Error::Decrypt => write!(f, "resource archive can't be decrypted with the key"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match error {
+            Error::NotFound => std::io::ErrorKind::NotFound,
+            Error::NotAFile => std::io::ErrorKind::InvalidInput,
+            Error::Malformed => std::io::ErrorKind::InvalidData,
+            Error::Unsupported => std::io::ErrorKind::Unsupported,
+            Error::Decrypt => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
+/// How many symbolic links are followed while looking up a file.
+const MAX_LINKS: usize = 40;
+
+/// A view of the bundled resource files.
+pub struct Resources<T> {
+    data: T,
+}
+
+impl<T: AsRef<[u8]>> Resources<T> {
+    /// Wraps the contents of the resources section, as provided by
+    /// the host.
+    ///
+    /// Compressed archives must be loaded with [`Resources::decode`]
+    /// instead, and encrypted ones with `Resources::decrypt`.
+    pub fn new(data: T) -> Self {
+        Self { data }
+    }
+
+    fn find(&self, path: &[u8]) -> Result<Option<tar::Entry<'_>>, Error> {
+        let mut result = None;
+
+        // Later entries replace earlier ones with the same path, as
+        // when extracting the archive.
+        for entry in tar::Entries::new(self.data.as_ref()) {
+            let entry = entry?;
+            if entry.matches(path) {
+                result = Some(entry);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the contents of the file at `path`.
+    ///
+    /// Symbolic links along the path and hard links are followed; a
+    /// link that leads outside of the bundle, or too many of them, is
+    /// reported as [`Error::NotFound`].
+    ///
+    /// The returned slice implements `Read` when the `std` feature is
+    /// enabled.
+    pub fn open(&self, path: &str) -> Result<&[u8], Error> {
+        let data = self.data.as_ref();
+        if data.starts_with(codec::MAGIC) || data.starts_with(crypt::MAGIC) {
+            return Err(Error::Unsupported);
+        }
+
+        let mut pending = PathBuf::new();
+        pending.extend(path.as_bytes())?;
+        let mut resolved = PathBuf::new();
+        let mut links = 0;
+
+        while let Some((component, rest)) = split_first(pending.as_bytes()) {
+            let mut next = PathBuf::new();
+
+            if component == b".." {
+                if !resolved.pop() {
+                    return Err(Error::NotFound);
+                }
+                next.extend(rest)?;
+                pending = next;
+                continue;
+            }
+            resolved.push(component)?;
+
+            match self.find(resolved.as_bytes())? {
+                Some(entry) if entry.kind == tar::SYMLINK => {
+                    links += 1;
+                    if links > MAX_LINKS || entry.link.starts_with(b"/") {
+                        return Err(Error::NotFound);
+                    }
+                    resolved.pop();
+                    next.extend(entry.link)?;
+                    next.push(rest)?;
+                }
+                // Directories need not have entries of their own.
+                _ => next.extend(rest)?,
+            }
+            pending = next;
+        }
+
+        if resolved.is_empty() {
+            return Err(Error::NotFound);
+        }
+        let mut entry = self.find(resolved.as_bytes())?.ok_or(Error::NotFound)?;
+        if entry.kind == tar::LINK {
+            entry = self.find(entry.link)?.ok_or(Error::NotFound)?;
+        }
+
+        match entry.kind {
+            tar::REGULAR | tar::OLD_REGULAR | tar::CONTIGUOUS => Ok(entry.data),
+            _ => Err(Error::NotAFile),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Resources<Vec<u8>> {
+    /// Copies the contents of the resources section, decompressing
+    /// them if needed.
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        match codec::decode(data)? {
+            Some(archive) => Ok(Self::new(archive)),
+            None => Ok(Self::new(data.to_vec())),
+        }
+    }
+
+    /// Copies the contents of the resources section, decrypting them
+    /// with `key` and decompressing them if needed.
+    #[cfg(feature = "decrypt")]
+    pub fn decrypt(data: &[u8], key: &[u8; 32]) -> Result<Self, Error> {
+        match crypt::decrypt(data, key)? {
+            Some(payload) => Self::decode(&payload),
+            None => Self::decode(data),
+        }
+    }
+
+    /// Reads the resources from the file at `path`, typically a file
+    /// in a directory preopened by the host, decompressing them if
+    /// needed.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        match codec::decode(&data)? {
+            Some(archive) => Ok(Self::new(archive)),
+            None => Ok(Self::new(data)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn resources() -> Resources<Vec<u8>> {
+        let mut builder = ::tar::Builder::new(Vec::new());
+        for (path, data) in &[("dir/file", "old"), ("dir/file", "new"), ("other", "other")] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        for (kind, path, target) in &[
+            (::tar::EntryType::Symlink, "up", ".."),
+            (::tar::EntryType::Symlink, "dir/sibling", "../other"),
+            (::tar::EntryType::Symlink, "alias", "dir"),
+            (::tar::EntryType::Symlink, "absolute", "/other"),
+            (::tar::EntryType::Symlink, "loop", "loop"),
+            (::tar::EntryType::Symlink, "dangling", "missing"),
+            (::tar::EntryType::Link, "hard", "dir/file"),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        Resources::new(builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn open_files() {
+        let resources = resources();
+        assert_eq!(resources.open("dir/file"), Ok(&b"new"[..]));
+        assert_eq!(resources.open("/./dir//file"), Ok(&b"new"[..]));
+        assert_eq!(resources.open("dir/../other"), Ok(&b"other"[..]));
+        assert_eq!(resources.open("dir"), Err(Error::NotFound));
+        assert_eq!(resources.open(""), Err(Error::NotFound));
+        assert_eq!(resources.open("missing"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn open_links() {
+        let resources = resources();
+        assert_eq!(resources.open("alias/file"), Ok(&b"new"[..]));
+        assert_eq!(resources.open("alias/sibling"), Ok(&b"other"[..]));
+        assert_eq!(resources.open("hard"), Ok(&b"new"[..]));
+        assert_eq!(resources.open("alias/../other"), Ok(&b"other"[..]));
+        assert_eq!(resources.open("up"), Err(Error::NotFound));
+        assert_eq!(resources.open("up/other"), Err(Error::NotFound));
+        assert_eq!(resources.open("absolute"), Err(Error::NotFound));
+        assert_eq!(resources.open("loop"), Err(Error::NotFound));
+        assert_eq!(resources.open("dangling"), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn open_encoded() {
+        let mut data = codec::MAGIC.to_vec();
+        data.push(1);
+        assert_eq!(Resources::new(data).open("file"), Err(Error::Unsupported));
+    }
+}
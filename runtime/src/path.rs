@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::Error;
+
+/// The longest path, after following links, that can be looked up.
+const MAX_PATH: usize = 4096;
+
+/// A path in a fixed buffer, as there is no allocator to rely on.
+pub(crate) struct PathBuf {
+    buf: [u8; MAX_PATH],
+    len: usize,
+}
+
+impl PathBuf {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: [0; MAX_PATH],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn extend(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        if end > MAX_PATH {
+            return Err(Error::NotFound);
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Appends `component`, separated by a `/`.
+    pub(crate) fn push(&mut self, component: &[u8]) -> Result<(), Error> {
+        if self.len > 0 {
+            self.extend(b"/")?;
+        }
+        self.extend(component)
+    }
+
+    /// Removes the last component, returning whether there was one.
+    pub(crate) fn pop(&mut self) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        self.len = self.buf[..self.len]
+            .iter()
+            .rposition(|&b| b == b'/')
+            .unwrap_or(0);
+        true
+    }
+}
+
+/// Splits the first component off `path`, skipping empty and `.`
+/// components.
+pub(crate) fn split_first(mut path: &[u8]) -> Option<(&[u8], &[u8])> {
+    loop {
+        let (component, rest) = match path.iter().position(|&b| b == b'/') {
+            Some(n) => (&path[..n], &path[n + 1..]),
+            None => (path, &[][..]),
+        };
+        if !component.is_empty() && component != b"." {
+            return Some((component, rest));
+        }
+        if rest.is_empty() {
+            return None;
+        }
+        path = rest;
+    }
+}
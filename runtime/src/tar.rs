@@ -0,0 +1,352 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::Error;
+use core::convert::TryFrom;
+
+const BLOCK_SIZE: usize = 512;
+
+pub(crate) const REGULAR: u8 = b'0';
+pub(crate) const CONTIGUOUS: u8 = b'7';
+pub(crate) const OLD_REGULAR: u8 = b'\0';
+pub(crate) const LINK: u8 = b'1';
+pub(crate) const SYMLINK: u8 = b'2';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+const PAX_LOCAL: u8 = b'x';
+const PAX_GLOBAL: u8 = b'g';
+
+pub(crate) struct Entry<'a> {
+    prefix: &'a [u8],
+    name: &'a [u8],
+    pub(crate) kind: u8,
+    pub(crate) link: &'a [u8],
+    pub(crate) data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn matches(&self, path: &[u8]) -> bool {
+        let path = normalize(path);
+        let name = normalize(self.name);
+
+        if self.prefix.is_empty() {
+            return name == path;
+        }
+
+        let prefix = normalize(self.prefix);
+        path.len() == prefix.len() + 1 + name.len()
+            && path.starts_with(prefix)
+            && path[prefix.len()] == b'/'
+            && path.ends_with(name)
+    }
+}
+
+fn normalize(mut path: &[u8]) -> &[u8] {
+    loop {
+        if path.starts_with(b"./") {
+            path = &path[2..];
+        } else if path.starts_with(b"/") {
+            path = &path[1..];
+        } else {
+            break;
+        }
+    }
+
+    while path.ends_with(b"/") {
+        path = &path[..path.len() - 1];
+    }
+
+    path
+}
+
+fn cstr(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == 0) {
+        Some(n) => &field[..n],
+        None => field,
+    }
+}
+
+fn parse_size(field: &[u8]) -> Result<usize, Error> {
+    // GNU tar stores large sizes as big-endian binary, flagged by the
+    // high bit of the first byte.
+    if field[0] & 0x80 != 0 {
+        let mut size: u64 = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            size = size.checked_mul(256).ok_or(Error::Malformed)? | u64::from(b);
+        }
+        return usize::try_from(size).or(Err(Error::Malformed));
+    }
+
+    let mut size: usize = 0;
+    for &b in field {
+        match b {
+            b'0'..=b'7' => {
+                size = size
+                    .checked_mul(8)
+                    .and_then(|size| size.checked_add(usize::from(b - b'0')))
+                    .ok_or(Error::Malformed)?;
+            }
+            b' ' | 0 => {
+                if size > 0 {
+                    break;
+                }
+            }
+            _ => return Err(Error::Malformed),
+        }
+    }
+
+    Ok(size)
+}
+
+/// Returns the value of the last `key` record of a pax header.
+fn pax_record<'a>(mut records: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+    let mut value = None;
+
+    // Each record is of the form "<length> <key>=<value>\n", where
+    // length covers the whole record.
+    while !records.is_empty() {
+        let space = records
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or(Error::Malformed)?;
+        let len = core::str::from_utf8(&records[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= records.len())
+            .ok_or(Error::Malformed)?;
+        let record = &records[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(rest) = record.strip_prefix(key) {
+            if let Some(rest) = rest.strip_prefix(b"=") {
+                value = Some(rest);
+            }
+        }
+        records = &records[len..];
+    }
+
+    Ok(value)
+}
+
+/// Iterates over the entries of an in-memory tar archive.
+pub(crate) struct Entries<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Entries<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let mut long_name = None;
+        let mut long_link = None;
+
+        loop {
+            if self.data.is_empty() {
+                return Ok(None);
+            }
+            if self.data.len() < BLOCK_SIZE {
+                return Err(Error::Malformed);
+            }
+
+            let (header, rest) = self.data.split_at(BLOCK_SIZE);
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let size = parse_size(&header[124..136])?;
+            let padded =
+                size.checked_add(BLOCK_SIZE - 1).ok_or(Error::Malformed)? / BLOCK_SIZE * BLOCK_SIZE;
+            if rest.len() < padded {
+                return Err(Error::Malformed);
+            }
+            let data = &rest[..size];
+            self.data = &rest[padded..];
+
+            let kind = header[156];
+            match kind {
+                GNU_LONG_NAME => long_name = Some(cstr(data)),
+                GNU_LONG_LINK => long_link = Some(cstr(data)),
+                PAX_LOCAL => {
+                    long_name = pax_record(data, b"path")?.or(long_name);
+                    long_link = pax_record(data, b"linkpath")?.or(long_link);
+                }
+                PAX_GLOBAL => {}
+                _ => {
+                    // Only POSIX ustar archives use the prefix field;
+                    // GNU archives store other data there.
+                    let prefix = if long_name.is_none() && &header[257..263] == b"ustar\0" {
+                        cstr(&header[345..500])
+                    } else {
+                        &[]
+                    };
+                    let name = long_name.unwrap_or_else(|| cstr(&header[..100]));
+                    let link = long_link.unwrap_or_else(|| cstr(&header[157..257]));
+                    return Ok(Some(Entry {
+                        prefix,
+                        name,
+                        kind,
+                        link,
+                        data,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => None,
+            Err(e) => {
+                // Stop at the first error rather than reading garbage.
+                self.data = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn path(entry: &Entry<'_>) -> Vec<u8> {
+        let mut path = entry.prefix.to_vec();
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+        path.extend_from_slice(entry.name);
+        path
+    }
+
+    /// Checks that the entries parsed match those read by the `tar`
+    /// crate.
+    fn check(archive: &[u8]) {
+        let expected: Vec<_> = ::tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path_bytes().into_owned();
+                let link = entry.link_name_bytes().map(|link| link.into_owned());
+                let mut data = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+                (path, link.unwrap_or_default(), data)
+            })
+            .collect();
+        let actual: Vec<_> = Entries::new(archive)
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (path(&entry), entry.link.to_vec(), entry.data.to_vec())
+            })
+            .collect();
+
+        assert_eq!(actual, expected);
+        for (path, ..) in &expected {
+            let entries = Entries::new(archive).map(Result::unwrap);
+            assert_eq!(entries.filter(|entry| entry.matches(path)).count(), 1);
+        }
+    }
+
+    fn append(builder: &mut ::tar::Builder<Vec<u8>>, mut header: ::tar::Header, path: &str) {
+        let data = path.as_bytes();
+        header.set_size(data.len() as u64);
+        header.set_entry_type(::tar::EntryType::Regular);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn append_link(builder: &mut ::tar::Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = ::tar::Header::new_gnu();
+        header.set_entry_type(::tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    #[test]
+    fn parse_size_octal() {
+        assert_eq!(parse_size(b"00000001750\0"), Ok(1000));
+        assert_eq!(parse_size(b"     1750 \0\0"), Ok(1000));
+        assert_eq!(parse_size(b"\0\0\0\0\0\0\0\0\0\0\0\0"), Ok(0));
+        assert_eq!(parse_size(b"0000000175x\0"), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn parse_size_base_256() {
+        let mut field = [0; 12];
+        field[0] = 0x80;
+        field[7] = 0x02;
+        field[11] = 0x01;
+        assert_eq!(parse_size(&field), Ok(0x2_0000_0001));
+
+        let mut field = [0xff; 12];
+        field[0] = 0x80;
+        assert_eq!(parse_size(&field), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn pax_records() {
+        let records = b"19 path=first/name\n20 path=second/name\n16 linkpath=tgt\n";
+        assert_eq!(pax_record(records, b"path"), Ok(Some(&b"second/name"[..])));
+        assert_eq!(pax_record(records, b"linkpath"), Ok(Some(&b"tgt"[..])));
+        assert_eq!(pax_record(records, b"size"), Ok(None));
+        assert_eq!(pax_record(b"99 path=x\n", b"path"), Err(Error::Malformed));
+        assert_eq!(pax_record(b"path=x\n", b"path"), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn gnu_long_names() {
+        let long = "d/".repeat(80) + "file";
+        let mut builder = ::tar::Builder::new(Vec::new());
+        append(&mut builder, ::tar::Header::new_gnu(), "short");
+        append(&mut builder, ::tar::Header::new_gnu(), &long);
+        append_link(&mut builder, "link", &long);
+        append_link(&mut builder, &(long.clone() + "-link"), "short");
+        check(&builder.into_inner().unwrap());
+    }
+
+    #[test]
+    fn ustar_prefix() {
+        let long = "p/".repeat(70) + "file";
+        let mut builder = ::tar::Builder::new(Vec::new());
+        append(&mut builder, ::tar::Header::new_ustar(), &long);
+        append(&mut builder, ::tar::Header::new_ustar(), "short");
+        let archive = builder.into_inner().unwrap();
+        assert_ne!(archive[345], 0);
+        check(&archive);
+    }
+
+    #[test]
+    fn pax_headers() {
+        let long = "x/".repeat(80) + "file";
+        let mut builder = ::tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([("path", long.as_bytes()), ("linkpath", &b"short"[..])])
+            .unwrap();
+        let mut header = ::tar::Header::new_ustar();
+        header.set_entry_type(::tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "ignored", "ignored")
+            .unwrap();
+        append(&mut builder, ::tar::Header::new_ustar(), "short");
+        check(&builder.into_inner().unwrap());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut builder = ::tar::Builder::new(Vec::new());
+        append(&mut builder, ::tar::Header::new_gnu(), "file");
+        let archive = builder.into_inner().unwrap();
+
+        let mut entries = Entries::new(&archive[..BLOCK_SIZE + 1]);
+        assert!(matches!(entries.next(), Some(Err(Error::Malformed))));
+        assert!(entries.next().is_none());
+    }
+}
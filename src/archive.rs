@@ -0,0 +1,690 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::codec::open_archive;
+use crate::crypt::EncryptionKey;
+use crate::error::{BundleError, PathContext, Result};
+use crate::glob::{is_ignored, read_ignore_files, Globs};
+use crate::section::find_section;
+use ignore::gitignore::Gitignore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) enum Source {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+    Directory(PathBuf, Globs),
+}
+
+pub(crate) struct Entry {
+    pub(crate) name: PathBuf,
+    pub(crate) source: Source,
+}
+
+fn check_ancestors(path: &Path) -> Result<()> {
+    for ancestor in path.ancestors() {
+        if ancestor == Path::new("") {
+            break;
+        }
+        let metadata = std::fs::metadata(ancestor).with_path(ancestor)?;
+        if !metadata.is_dir() && !metadata.is_file() {
+            return Err(BundleError::SpecialFile(ancestor.to_path_buf()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns `name` into a relative path within the archive, dropping any
+/// leading `/`.
+fn archive_name(name: &Path) -> Result<PathBuf> {
+    let mut result = PathBuf::new();
+
+    for component in name.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::RootDir | Component::CurDir => {}
+            _ => return Err(BundleError::InvalidName(name.to_path_buf())),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns whether `target`, relative to the directory `base` of the
+/// bundle, stays inside the bundle.
+pub(crate) fn link_inside(base: &Path, target: &Path) -> bool {
+    let mut depth = base.components().count();
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+pub(crate) struct Options {
+    /// Normalize the metadata with the given modification time,
+    /// rather than recording it from the file system.
+    pub(crate) mtime: Option<u64>,
+    /// Record symbolic links and files sharing an inode as links,
+    /// rather than following them.
+    pub(crate) links: bool,
+    /// Store files with the same contents and mode once, with hard
+    /// links to the first one.
+    pub(crate) dedup: bool,
+}
+
+/// What went into a bundle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of files and directories bundled.
+    pub entries: usize,
+    /// The number of files stored as links to an identical one.
+    pub links: usize,
+    /// The size of the contents that didn't need to be stored again.
+    pub bytes_saved: u64,
+}
+
+pub(crate) enum Item<'a> {
+    File(PathBuf),
+    Bytes(&'a [u8]),
+    Directory(PathBuf),
+    Symlink(PathBuf, PathBuf),
+}
+
+/// Returns the symbolic link item for `path`, stored as `name`, if it
+/// is one and links are preserved.
+fn symlink<'a>(path: &Path, name: &Path, options: &Options) -> Result<Option<Item<'a>>> {
+    if !options.links
+        || !std::fs::symlink_metadata(path)
+            .with_path(path)?
+            .file_type()
+            .is_symlink()
+    {
+        return Ok(None);
+    }
+
+    let target = std::fs::read_link(path).with_path(path)?;
+    if !link_inside(name.parent().unwrap_or_else(|| Path::new("")), &target) {
+        return Err(BundleError::UnsafeLink {
+            path: path.to_path_buf(),
+            target,
+        });
+    }
+
+    Ok(Some(Item::Symlink(path.to_path_buf(), target)))
+}
+
+fn walk_directory<'a>(
+    path: &Path,
+    name: &Path,
+    relative: &Path,
+    globs: &Globs,
+    options: &Options,
+    ignores: &mut Vec<Gitignore>,
+    items: &mut Vec<(PathBuf, Item<'a>)>,
+) -> Result<()> {
+    if name != Path::new("") {
+        items.push((name.to_path_buf(), Item::Directory(path.to_path_buf())));
+    }
+
+    if globs.ignore_files() {
+        ignores.push(read_ignore_files(path)?);
+    }
+
+    // Sort the children so the archive layout doesn't depend on the
+    // order the file system happens to return them in.
+    let mut children = std::fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .with_path(path)?;
+    children.sort();
+
+    for child in children {
+        let path = path.join(&child);
+        let name = name.join(&child);
+        let relative = relative.join(&child);
+
+        if let Some(item) = symlink(&path, &name, options)? {
+            if !is_ignored(ignores, &path, false) && globs.is_included(&relative) {
+                items.push((name, item));
+            }
+            continue;
+        }
+
+        let metadata = std::fs::metadata(&path).with_path(&path)?;
+        if is_ignored(ignores, &path, metadata.is_dir()) {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if !globs.is_excluded(&relative) {
+                walk_directory(&path, &name, &relative, globs, options, ignores, items)?;
+            }
+        } else if metadata.is_file() {
+            if globs.is_included(&relative) {
+                items.push((name, Item::File(path)));
+            }
+        } else {
+            return Err(BundleError::SpecialFile(path));
+        }
+    }
+
+    if globs.ignore_files() {
+        ignores.pop();
+    }
+
+    Ok(())
+}
+
+fn append_path<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    options: &Options,
+) -> Result<()> {
+    let mtime = match options.mtime {
+        Some(mtime) => mtime,
+        None => return builder.append_path_with_name(path, name).with_path(path),
+    };
+
+    let file = std::fs::File::open(path).with_path(path)?;
+    let metadata = file.metadata().with_path(path)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tar::HeaderMode::Deterministic);
+    header.set_mtime(mtime);
+
+    if metadata.is_dir() {
+        builder.append_data(&mut header, name, std::io::empty())?;
+    } else {
+        builder
+            .append_data(&mut header, name, file)
+            .with_path(path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the header for a link entry with the given metadata.
+fn link_header(metadata: &Metadata, kind: tar::EntryType, options: &Options) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    match options.mtime {
+        Some(mtime) => {
+            header.set_metadata_in_mode(metadata, tar::HeaderMode::Deterministic);
+            header.set_mtime(mtime);
+        }
+        None => header.set_metadata(metadata),
+    }
+    header.set_entry_type(kind);
+    header.set_size(0);
+    header
+}
+
+/// Identifies the file with the given metadata if other names refer
+/// to it.
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Returns the permissions of the file, as they would be stored in
+/// the archive.
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Tracks the files in the archive that later ones can link to.
+#[derive(Default)]
+struct Links {
+    inodes: HashMap<(u64, u64), PathBuf>,
+    contents: HashMap<(u32, [u8; 32]), PathBuf>,
+}
+
+impl Links {
+    /// Returns the name of an earlier file with the given inode or
+    /// mode and contents, or remembers `name` as the one to link to.
+    fn first(
+        &mut self,
+        inode: Option<(u64, u64)>,
+        contents: Option<(u32, [u8; 32])>,
+        name: &Path,
+    ) -> Option<PathBuf> {
+        let first = inode
+            .and_then(|inode| self.inodes.get(&inode))
+            .or_else(|| contents.and_then(|contents| self.contents.get(&contents)));
+        if first.is_some() {
+            return first.cloned();
+        }
+
+        if let Some(inode) = inode {
+            self.inodes.insert(inode, name.to_path_buf());
+        }
+        if let Some(contents) = contents {
+            self.contents.insert(contents, name.to_path_buf());
+        }
+        None
+    }
+}
+
+pub(crate) fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path).with_path(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_path(path)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Returns the files and directories to bundle for `entries`, walking
+/// directories recursively, along with their names in the bundle.
+pub(crate) fn collect_items<'a>(
+    entries: &'a [Entry],
+    options: &Options,
+) -> Result<Vec<(PathBuf, Item<'a>)>> {
+    let mut items = Vec::new();
+
+    for entry in entries {
+        let name = archive_name(&entry.name)?;
+        match &entry.source {
+            Source::File(path) => {
+                check_ancestors(path)?;
+                match symlink(path, &name, options)? {
+                    Some(item) => items.push((name, item)),
+                    None => items.push((name, Item::File(path.clone()))),
+                }
+            }
+            Source::Bytes(data) => items.push((name, Item::Bytes(data))),
+            Source::Directory(path, globs) => {
+                check_ancestors(path)?;
+                walk_directory(
+                    path,
+                    &name,
+                    Path::new(""),
+                    globs,
+                    options,
+                    &mut Vec::new(),
+                    &mut items,
+                )?;
+            }
+        }
+    }
+
+    Ok(items)
+}
+
+pub(crate) fn create_archive(
+    entries: &[Entry],
+    options: &Options,
+    writer: &mut impl Write,
+) -> Result<Stats> {
+    let mut items = collect_items(entries, options)?;
+    if options.mtime.is_some() {
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    let mut builder = tar::Builder::new(writer);
+    let mut links = Links::default();
+    let mut stats = Stats {
+        entries: items.len(),
+        ..Stats::default()
+    };
+
+    for (name, item) in items {
+        match item {
+            Item::File(path) if options.links || options.dedup => {
+                let metadata = std::fs::metadata(&path).with_path(&path)?;
+                let inode = inode(&metadata).filter(|_| options.links);
+                // Empty files have nothing to save.
+                let contents = if options.dedup && metadata.len() > 0 {
+                    Some((mode(&metadata), hash_file(&path)?))
+                } else {
+                    None
+                };
+
+                match links.first(inode, contents, &name) {
+                    Some(first) => {
+                        let mut header = link_header(&metadata, tar::EntryType::Link, options);
+                        builder.append_link(&mut header, &name, first)?;
+                        stats.links += 1;
+                        stats.bytes_saved += metadata.len();
+                    }
+                    None => append_path(&mut builder, &path, &name, options)?,
+                }
+            }
+            Item::Symlink(path, target) => {
+                let metadata = std::fs::symlink_metadata(&path).with_path(&path)?;
+                let mut header = link_header(&metadata, tar::EntryType::Symlink, options);
+                builder.append_link(&mut header, &name, target)?;
+            }
+            Item::File(path) | Item::Directory(path) => {
+                append_path(&mut builder, &path, &name, options)?;
+            }
+            Item::Bytes(data) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_mtime(options.mtime.unwrap_or(0));
+                header.set_size(data.len() as u64);
+
+                let contents = if options.dedup && !data.is_empty() {
+                    Some((0o644, Sha256::digest(data).into()))
+                } else {
+                    None
+                };
+                match links.first(None, contents, &name) {
+                    Some(first) => {
+                        header.set_entry_type(tar::EntryType::Link);
+                        header.set_size(0);
+                        builder.append_link(&mut header, &name, first)?;
+                        stats.links += 1;
+                        stats.bytes_saved += data.len() as u64;
+                    }
+                    None => builder.append_data(&mut header, &name, data)?,
+                }
+            }
+        }
+    }
+
+    builder.finish()?;
+
+    Ok(stats)
+}
+
+/// Unpacks the files bundled in the custom section named `section` of
+/// the Wasm module `input` into `dir`.
+pub fn extract(section: &str, input: &[u8], dir: &Path) -> Result<()> {
+    extract_with_key(section, input, dir, None)
+}
+
+/// Like [`extract`], but decrypting the files with `key` if they are
+/// encrypted.
+pub fn extract_with_key(
+    section: &str,
+    input: &[u8],
+    dir: &Path,
+    key: Option<&EncryptionKey>,
+) -> Result<()> {
+    let (_, data) = find_section(section, input)?
+        .ok_or_else(|| BundleError::SectionNotFound(section.to_string()))?;
+    let invalid_archive = |e| BundleError::invalid_archive(section, e);
+    let data = open_archive(section, data, key)?;
+
+    // Check the whole archive before writing anything, since a link is
+    // only safe given the symbolic links it goes through, which may come
+    // later in the archive.
+    let mut paths = Vec::new();
+    let mut links = Vec::new();
+    let mut symlinks = HashMap::new();
+    for entry in tar::Archive::new(data.as_ref())
+        .entries()
+        .map_err(invalid_archive)?
+    {
+        let entry = entry.map_err(invalid_archive)?;
+
+        // Refuse to write anything outside of the destination
+        // directory, rather than silently skipping it.
+        let path = entry.path().map_err(invalid_archive)?.into_owned();
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(BundleError::UnsafePath(path));
+        }
+        let path: PathBuf = path.components().collect();
+
+        // A symbolic link is kept when a directory is unpacked over it,
+        // so remember every one, with the last target.
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = entry
+                .link_name()
+                .map_err(invalid_archive)?
+                .unwrap_or_default()
+                .into_owned();
+            if kind.is_symlink() {
+                symlinks.insert(path.clone(), target.clone());
+            }
+            links.push((path.clone(), kind, target));
+        }
+        paths.push(path);
+    }
+
+    // Nothing is written through a symbolic link, which could lead
+    // anywhere once extracted.
+    for path in &paths {
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| symlinks.contains_key(ancestor))
+        {
+            return Err(BundleError::UnsafePath(path.clone()));
+        }
+    }
+
+    // Symbolic links are relative to their directory, and hard links to
+    // the root of the bundle.
+    for (path, kind, target) in links {
+        let base = match kind {
+            tar::EntryType::Symlink => path.parent().unwrap_or_else(|| Path::new("")),
+            _ => Path::new(""),
+        };
+        if target.has_root()
+            || resolve(&base.join(&target), |path| symlinks.get(path).cloned()).is_none()
+        {
+            return Err(BundleError::UnsafeLink { path, target });
+        }
+    }
+
+    let existed = dir.exists();
+    std::fs::create_dir_all(dir).with_path(dir)?;
+    let mut created = Vec::new();
+    let result = unpack(section, &data, dir, &mut created);
+    if result.is_err() {
+        // Don't leave a partial extraction behind.
+        for path in created.iter().rev() {
+            let _ = match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir(path),
+                _ => std::fs::remove_file(path),
+            };
+        }
+        if !existed {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+
+    result
+}
+
+/// Unpacks the checked tar archive `data` into `dir`, recording the
+/// files and directories created, parents first.
+fn unpack(section: &str, data: &[u8], dir: &Path, created: &mut Vec<PathBuf>) -> Result<()> {
+    let invalid_archive = |e| BundleError::invalid_archive(section, e);
+
+    for entry in tar::Archive::new(data).entries().map_err(invalid_archive)? {
+        let mut entry = entry.map_err(invalid_archive)?;
+        let path = dir.join(entry.path().map_err(invalid_archive)?);
+
+        let mut new: Vec<_> = path
+            .ancestors()
+            .take_while(|path| *path != dir && std::fs::symlink_metadata(path).is_err())
+            .map(Path::to_path_buf)
+            .collect();
+        new.reverse();
+        created.append(&mut new);
+
+        entry.unpack_in(dir).with_path(&path)?;
+    }
+
+    Ok(())
+}
+
+/// How many symbolic links are followed while resolving a path.
+pub(crate) const MAX_LINKS: usize = 40;
+
+/// Resolves `path` within the bundle, following `..` components and the
+/// symbolic links whose targets `symlink` returns.
+///
+/// Returns `None` if that leads outside of the bundle, or through too
+/// many links.
+pub(crate) fn resolve(path: &Path, symlink: impl Fn(&Path) -> Option<PathBuf>) -> Option<PathBuf> {
+    let mut pending: Vec<OsString> = path
+        .components()
+        .rev()
+        .filter(|component| matches!(component, Component::Normal(_) | Component::ParentDir))
+        .map(|component| component.as_os_str().to_os_string())
+        .collect();
+    let mut path = PathBuf::new();
+    let mut links = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            if !path.pop() {
+                return None;
+            }
+            continue;
+        }
+        path.push(component);
+
+        // Directories need not have entries of their own.
+        let target = match symlink(&path) {
+            Some(target) => target,
+            None => continue,
+        };
+        links += 1;
+        if links > MAX_LINKS || target.has_root() {
+            return None;
+        }
+
+        path.pop();
+        for component in target.iter().rev() {
+            if component != "." {
+                pending.push(component.to_os_string());
+            }
+        }
+    }
+
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::bundle_module;
+    use crate::RESOURCES_SECTION;
+
+    /// Returns a module bundling the symbolic links `links`, given as
+    /// paths and targets, in that order.
+    fn symlinks(links: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, target) in links {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_link_name_literal(target).unwrap();
+            builder.append_data(&mut header, path, &[][..]).unwrap();
+        }
+        bundle_module(&builder.into_inner().unwrap())
+    }
+
+    fn extract_links(links: &[(&str, &str)]) -> (tempfile::TempDir, Result<()>) {
+        let dir = tempfile::tempdir().unwrap();
+        let result = extract(RESOURCES_SECTION, &symlinks(links), &dir.path().join("out"));
+        (dir, result)
+    }
+
+    #[test]
+    fn extract_links_inside() {
+        let (dir, result) = extract_links(&[("d/up", ".."), ("d/up2", "up/d/up")]);
+        result.unwrap();
+        let link = dir.path().join("out/d/up2");
+        assert_eq!(std::fs::read_link(link).unwrap(), Path::new("up/d/up"));
+    }
+
+    #[test]
+    fn extract_through_symlink() {
+        let (dir, result) = extract_links(&[("d/up", ".."), ("d/up/x", "../..")]);
+        assert!(
+            matches!(result, Err(BundleError::UnsafePath(path)) if path == Path::new("d/up/x"))
+        );
+        assert!(!dir.path().join("out").exists());
+        assert!(!dir.path().join("x").exists());
+    }
+
+    #[test]
+    fn extract_link_through_later_symlink() {
+        let (dir, result) = extract_links(&[("a", "c/d/../.."), ("c/d", "..")]);
+        assert!(
+            matches!(result, Err(BundleError::UnsafeLink { path, .. }) if path == Path::new("a"))
+        );
+        assert!(!dir.path().join("out").exists());
+    }
+
+    #[test]
+    fn extract_cleans_up() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in &["f", "f/g"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            builder.append_data(&mut header, path, &b"x"[..]).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("kept"), b"").unwrap();
+        let result = extract(
+            RESOURCES_SECTION,
+            &bundle_module(&builder.into_inner().unwrap()),
+            dir.path(),
+        );
+        assert!(matches!(result, Err(BundleError::Io { .. })));
+        assert!(!dir.path().join("f").exists());
+        assert!(dir.path().join("kept").exists());
+    }
+
+    #[test]
+    fn resolve_links() {
+        let symlinks: HashMap<PathBuf, PathBuf> = vec![
+            (PathBuf::from("a"), PathBuf::from("b/c")),
+            (PathBuf::from("loop"), PathBuf::from("loop")),
+        ]
+        .into_iter()
+        .collect();
+        let resolve = |path: &str| resolve(Path::new(path), |path| symlinks.get(path).cloned());
+
+        assert_eq!(resolve("a/../d"), Some(PathBuf::from("b/d")));
+        assert_eq!(resolve("x/../a/e"), Some(PathBuf::from("b/c/e")));
+        assert_eq!(resolve("a/../../.."), None);
+        assert_eq!(resolve("loop"), None);
+    }
+}
@@ -0,0 +1,456 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::archive::{create_archive, Entry, Options, Source, Stats};
+use crate::codec::{encode, Compression};
+use crate::component::{locate, Target};
+use crate::crypt::{encrypt, Cipher, EncryptionKey};
+use crate::digest::Digests;
+use crate::error::{BundleError, PathContext, Result};
+use crate::glob::Globs;
+use crate::section::{splice, Position};
+use crate::signature::Scope;
+use crate::validate::Validating;
+use crate::RESOURCES_SECTION;
+use ed25519_dalek::SigningKey;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use wasmparser::WasmFeatures;
+
+/// Collects resource files and bundles them into a Wasm module.
+///
+/// ```no_run
+/// # fn main() -> wasm_bundle::Result<()> {
+/// let input = std::fs::read("input.wasm")?;
+/// let mut output = std::fs::File::create("output.wasm")?;
+///
+/// wasm_bundle::BundleBuilder::new()
+///     .add_file("dir/config.toml", "config.toml")
+///     .add_bytes("version.txt", "1.0.0")
+///     .add_directory("dir/assets", "assets")
+///     .write(input.as_slice(), &mut output)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BundleBuilder {
+    section: String,
+    entries: Vec<Entry>,
+    options: Options,
+    compression: Compression,
+    position: Position,
+    target: Target,
+    features: Option<WasmFeatures>,
+    digests: bool,
+    encryption: Option<(Cipher, EncryptionKey)>,
+    key: Option<(SigningKey, Scope)>,
+}
+
+impl Default for BundleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BundleBuilder {
+    /// Creates a builder with no files, targeting the default
+    /// `.enarx.resources` section.
+    pub fn new() -> Self {
+        Self {
+            section: RESOURCES_SECTION.to_string(),
+            entries: Vec::new(),
+            options: Options {
+                mtime: None,
+                links: false,
+                dedup: false,
+            },
+            compression: Compression::None,
+            position: Position::End,
+            target: Target::TopLevel,
+            features: Some(WasmFeatures::default()),
+            digests: false,
+            encryption: None,
+            key: None,
+        }
+    }
+
+    /// Sets the name of the custom section to write the resources to.
+    pub fn section(&mut self, name: &str) -> &mut Self {
+        self.section = name.to_string();
+        self
+    }
+
+    /// Makes the output depend only on the names and contents of the
+    /// bundled files: entries are sorted by name, ownership is cleared,
+    /// modification times are set to `mtime`, and modes are reduced to
+    /// 0644 or 0755.
+    pub fn reproducible(&mut self, mtime: u64) -> &mut Self {
+        self.options.mtime = Some(mtime);
+        self
+    }
+
+    /// Sets the compression applied to the bundled files.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets where the resources section is placed in the module; by
+    /// default it comes last.
+    pub fn position(&mut self, position: Position) -> &mut Self {
+        self.position = position;
+        self
+    }
+
+    /// Records symbolic links as links, provided they point inside the
+    /// bundle, and files with several names among the bundled ones as
+    /// hard links, rather than storing the contents again.
+    pub fn preserve_links(&mut self, preserve: bool) -> &mut Self {
+        self.options.links = preserve;
+        self
+    }
+
+    /// Stores files with the same contents and permissions once, with
+    /// the others as hard links to it.
+    pub fn deduplicate(&mut self, dedup: bool) -> &mut Self {
+        self.options.dedup = dedup;
+        self
+    }
+
+    /// Sets which module of a component the resources are bundled
+    /// into; by default it is the top-level module or component.
+    pub fn module(&mut self, target: Target) -> &mut Self {
+        self.target = target;
+        self
+    }
+
+    /// Records the SHA-256 digest of each bundled file in a companion
+    /// section named after the resources section with a `.digest`
+    /// suffix.
+    pub fn digests(&mut self, enabled: bool) -> &mut Self {
+        self.digests = enabled;
+        self
+    }
+
+    /// Encrypts the bundled files with `key`, after compressing them,
+    /// so that only those holding the key can read them.  This can't be
+    /// combined with [`digests`](Self::digests), which would list the
+    /// files in the clear.
+    pub fn encrypt(&mut self, cipher: Cipher, key: EncryptionKey) -> &mut Self {
+        self.encryption = Some((cipher, key));
+        self
+    }
+
+    /// Signs the resources section with `key`, covering the rest of the
+    /// module too depending on `scope`, in a companion section named
+    /// after it with a `.sig` suffix.
+    pub fn sign(&mut self, key: SigningKey, scope: Scope) -> &mut Self {
+        self.key = Some((key, scope));
+        self
+    }
+
+    /// Adds the file at `path`, stored as `name` in the bundle.
+    ///
+    /// Here and below, a leading `/` in `name` is ignored.
+    pub fn add_file(&mut self, path: impl AsRef<Path>, name: impl AsRef<Path>) -> &mut Self {
+        self.entries.push(Entry {
+            name: name.as_ref().to_path_buf(),
+            source: Source::File(path.as_ref().to_path_buf()),
+        });
+        self
+    }
+
+    /// Adds `data` as a regular file stored as `name` in the bundle.
+    pub fn add_bytes(&mut self, name: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.entries.push(Entry {
+            name: name.as_ref().to_path_buf(),
+            source: Source::Bytes(data.into()),
+        });
+        self
+    }
+
+    /// Recursively adds the directory at `path`, stored under `name` in
+    /// the bundle.
+    pub fn add_directory(&mut self, path: impl AsRef<Path>, name: impl AsRef<Path>) -> &mut Self {
+        self.add_directory_with_globs(path, name, Globs::default())
+    }
+
+    /// Recursively adds the files selected by `globs` from the
+    /// directory at `path`, stored under `name` in the bundle.
+    pub fn add_directory_with_globs(
+        &mut self,
+        path: impl AsRef<Path>,
+        name: impl AsRef<Path>,
+        globs: Globs,
+    ) -> &mut Self {
+        self.entries.push(Entry {
+            name: name.as_ref().to_path_buf(),
+            source: Source::Directory(path.as_ref().to_path_buf(), globs),
+        });
+        self
+    }
+
+    /// Sets whether the input module is validated, and the output
+    /// module checked, with the given proposals enabled.
+    ///
+    /// Validation is on with the default proposals of `wasmparser`
+    /// unless this is called with `None`.
+    pub fn validate(&mut self, features: Option<WasmFeatures>) -> &mut Self {
+        self.features = features;
+        self
+    }
+
+    /// Computes the digests of the files that would be bundled, as
+    /// [`ResourceReader`](crate::ResourceReader) would find them once
+    /// bundled, following links.
+    pub fn file_digests(&self) -> Result<Digests> {
+        Digests::from_entries(&self.entries)
+    }
+
+    /// Reads the Wasm module from `input` and writes it to `output`,
+    /// replacing any existing resources section with the collected
+    /// files, and returns what went into the bundle.
+    pub fn write(&self, input: impl Read, output: &mut impl Write) -> Result<Stats> {
+        if self.digests && self.encryption.is_some() {
+            return Err(BundleError::ConflictingOptions("digests", "encryption"));
+        }
+
+        let mut archive = tempfile::tempfile()?;
+        let stats = create_archive(&self.entries, &self.options, &mut archive)?;
+
+        let digests = if self.digests {
+            archive.seek(SeekFrom::Start(0))?;
+            Some(Digests::from_archive(&mut archive)?.to_bytes())
+        } else {
+            None
+        };
+
+        let mut payload = tempfile::tempfile()?;
+        archive.seek(SeekFrom::Start(0))?;
+        encode(self.compression, &mut archive, &mut payload)?;
+
+        if let Some((cipher, key)) = &self.encryption {
+            let mut compressed = Vec::new();
+            payload.seek(SeekFrom::Start(0))?;
+            payload.read_to_end(&mut compressed)?;
+            payload = tempfile::tempfile()?;
+            payload.write_all(&encrypt(*cipher, key, &compressed)?)?;
+        }
+
+        match self.features {
+            Some(features) => {
+                let mut input = Validating::new(input, features);
+                let mut output = Validating::new(output, features);
+                let result =
+                    self.write_module(&payload, digests.as_deref(), &mut input, &mut output);
+                output.finish(input.finish(result))?;
+            }
+            None => self.write_module(&payload, digests.as_deref(), input, output)?,
+        }
+
+        Ok(stats)
+    }
+
+    fn write_module(
+        &self,
+        payload: &std::fs::File,
+        digests: Option<&[u8]>,
+        mut input: impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let key = self.key.as_ref().map(|(key, scope)| (key, *scope));
+        if self.target == Target::TopLevel {
+            return splice(
+                &self.section,
+                self.position,
+                payload,
+                digests,
+                key,
+                input,
+                output,
+            );
+        }
+
+        // A nested module changes the size of the sections enclosing
+        // it, so the component is rewritten in memory.
+        let mut component = Vec::new();
+        input.read_to_end(&mut component)?;
+        let nested = locate(&component, &self.target)?;
+
+        let mut module = Vec::new();
+        let (_, input) = nested.module(&component);
+        splice(
+            &self.section,
+            self.position,
+            payload,
+            digests,
+            key,
+            input,
+            &mut module,
+        )?;
+        nested.replace(&component, &module, output)?;
+        Ok(())
+    }
+
+    /// Reads the Wasm module at `input` and writes it to the file at
+    /// `output`, which may be the same, replacing any existing resources
+    /// section with the collected files.
+    ///
+    /// The module is written to a temporary file in the same directory
+    /// as `output`, which then atomically replaces it, or the file it
+    /// links to if it is a symbolic link, so nothing is left behind on
+    /// failure.  Outputs that aren't regular files, such as
+    /// `/dev/stdout`, are written directly.
+    pub fn write_file(&self, input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<Stats> {
+        let input = input.as_ref();
+        let input = std::fs::File::open(input).with_path(input)?;
+        replace_file(output.as_ref(), |output| self.write(input, output))
+    }
+
+    /// Rewrites the Wasm module at `path`, replacing any existing
+    /// resources section with the collected files, as
+    /// [`write_file`](Self::write_file) does.
+    pub fn write_in_place(&self, path: impl AsRef<Path>) -> Result<Stats> {
+        self.write_file(&path, &path)
+    }
+}
+
+/// Writes the file at `path` with `write`, through a temporary file
+/// that replaces it only once written, keeping its permissions.
+fn replace_file<T>(path: &Path, write: impl FnOnce(&mut std::fs::File) -> Result<T>) -> Result<T> {
+    let path = &std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let mut builder = tempfile::Builder::new();
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_file() => {
+            let mut output = std::fs::File::create(path).with_path(path)?;
+            return write(&mut output);
+        }
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(_) => {
+            // Subject to the umask, as when creating the file directly.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                builder.permissions(std::fs::Permissions::from_mode(0o666));
+            }
+            None
+        }
+    };
+
+    let mut output = builder.tempfile_in(dir).with_path(dir)?;
+    let result = write(output.as_file_mut())?;
+    if let Some(permissions) = permissions {
+        output
+            .as_file()
+            .set_permissions(permissions)
+            .with_path(path)?;
+    }
+    output.persist(path).map_err(|e| e.error).with_path(path)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::{find_section, EMPTY_MODULE};
+
+    #[test]
+    fn write_file_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.wasm");
+        let output = dir.path().join("output.wasm");
+        std::fs::write(&input, EMPTY_MODULE).unwrap();
+        std::fs::write(&output, b"old").unwrap();
+
+        let result = BundleBuilder::new()
+            .add_bytes("a", "a")
+            .module(Target::Index(0))
+            .write_file(&input, &output);
+        assert!(matches!(result, Err(BundleError::ModuleNotFound(_))));
+        assert_eq!(std::fs::read(&output).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reproducible_output() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::write(tree.join("secret"), "secret").unwrap();
+        std::fs::write(tree.join("sub/file"), "file").unwrap();
+        std::fs::write(tree.join("run.sh"), "#!/bin/sh").unwrap();
+
+        let bundle = |modes: &[(&str, u32)], mtime: SystemTime| {
+            for (name, mode) in modes {
+                let path = tree.join(name);
+                let permissions = std::fs::Permissions::from_mode(*mode);
+                std::fs::set_permissions(&path, permissions).unwrap();
+                if path.is_file() {
+                    let file = std::fs::File::options().write(true).open(&path).unwrap();
+                    file.set_modified(mtime).unwrap();
+                }
+            }
+            let mut module = Vec::new();
+            BundleBuilder::new()
+                .reproducible(0)
+                .add_directory(&tree, "")
+                .write(EMPTY_MODULE, &mut module)
+                .unwrap();
+            module
+        };
+
+        let first = bundle(
+            &[
+                ("secret", 0o600),
+                ("sub", 0o755),
+                ("sub/file", 0o644),
+                ("run.sh", 0o755),
+            ],
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+        );
+        let second = bundle(
+            &[
+                ("secret", 0o644),
+                ("sub", 0o700),
+                ("sub/file", 0o600),
+                ("run.sh", 0o700),
+            ],
+            SystemTime::now(),
+        );
+        assert_eq!(first, second);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_in_place_through_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("module.wasm");
+        let link = dir.path().join("link.wasm");
+        std::fs::write(&module, EMPTY_MODULE).unwrap();
+        std::fs::set_permissions(&module, std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink("module.wasm", &link).unwrap();
+
+        BundleBuilder::new()
+            .add_bytes("a", "a")
+            .write_in_place(&link)
+            .unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let metadata = std::fs::metadata(&module).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        let bundled = std::fs::read(&module).unwrap();
+        assert!(find_section(RESOURCES_SECTION, &bundled).unwrap().is_some());
+    }
+}
@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::crypt::{decrypt, EncryptionKey};
+use crate::error::BundleError;
+use std::borrow::Cow;
+use std::io::{copy, ErrorKind, Read, Result, Write};
+use std::str::FromStr;
+
+/// Marks a resources section whose archive is compressed.
+///
+/// A plain tar archive never starts with a NUL byte followed by a
+/// non-NUL byte, so uncompressed sections stay readable as is.  The
+/// magic is followed by a byte identifying the [`Compression`] and the
+/// compressed archive.
+pub(crate) const MAGIC: &[u8] = b"\0wbundle";
+
+/// Compression applied to the bundled archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Store the archive as is.
+    None,
+    /// Compress with gzip.
+    Gzip,
+    /// Compress with Zstandard.
+    Zstd,
+    /// Compress with the LZ4 frame format.
+    Lz4,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+            Compression::Lz4 => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Gzip),
+            2 => Some(Compression::Zstd),
+            3 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BundleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(BundleError::InvalidOption {
+                name: "compression",
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Compresses the archive read from `input` into `output`, prefixed
+/// with the header identifying the compression.
+pub(crate) fn encode(
+    compression: Compression,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<()> {
+    if compression != Compression::None {
+        output.write_all(MAGIC)?;
+        output.write_all(&[compression.id()])?;
+    }
+
+    match compression {
+        Compression::None => {
+            copy(input, output)?;
+        }
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::best());
+            copy(input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(output, 19)?;
+            copy(input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(output);
+            copy(input, &mut encoder)?;
+            encoder.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the archive stored in a resources section, decompressing it
+/// if needed.
+pub(crate) fn decode(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let rest = match data.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None => return Ok(Cow::Borrowed(data)),
+    };
+    let (&id, compressed) = rest.split_first().ok_or(ErrorKind::InvalidData)?;
+    let compression = Compression::from_id(id).ok_or(ErrorKind::InvalidData)?;
+    let mut archive = Vec::new();
+
+    match compression {
+        Compression::None => return Ok(Cow::Borrowed(compressed)),
+        Compression::Gzip => {
+            flate2::read::GzDecoder::new(compressed).read_to_end(&mut archive)?;
+        }
+        Compression::Zstd => {
+            zstd::Decoder::new(compressed)?.read_to_end(&mut archive)?;
+        }
+        Compression::Lz4 => {
+            lz4_flex::frame::FrameDecoder::new(compressed).read_to_end(&mut archive)?;
+        }
+    }
+
+    Ok(Cow::Owned(archive))
+}
+
+/// Returns the archive stored in the resources section `section`,
+/// decrypting it with `key` and decompressing it as needed.
+pub(crate) fn open_archive<'a>(
+    section: &str,
+    data: &'a [u8],
+    key: Option<&EncryptionKey>,
+) -> crate::Result<Cow<'a, [u8]>> {
+    let invalid_archive = |e| BundleError::invalid_archive(section, e);
+    match decrypt(section, data, key)? {
+        Some(payload) => Ok(Cow::Owned(
+            decode(&payload).map_err(invalid_archive)?.into_owned(),
+        )),
+        None => decode(data).map_err(invalid_archive),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::{Cipher, EncryptionKey};
+    use crate::section::{find_section, EMPTY_MODULE};
+    use crate::{BundleBuilder, RESOURCES_SECTION};
+    use wasm_bundle_runtime::Resources;
+
+    const COMPRESSIONS: &[Compression] = &[
+        Compression::None,
+        Compression::Gzip,
+        Compression::Zstd,
+        Compression::Lz4,
+    ];
+
+    #[test]
+    fn encode_decode() {
+        let archive = b"archive ".repeat(100);
+        for compression in COMPRESSIONS {
+            let mut encoded = Vec::new();
+            encode(*compression, &mut archive.as_slice(), &mut encoded).unwrap();
+            if *compression != Compression::None {
+                assert!(encoded.starts_with(MAGIC));
+                assert!(encoded.len() < archive.len());
+            }
+            assert_eq!(decode(&encoded).unwrap(), &archive[..]);
+        }
+    }
+
+    #[test]
+    fn decode_unknown() {
+        let mut data = MAGIC.to_vec();
+        assert!(decode(&data).is_err());
+        data.push(4);
+        assert!(decode(&data).is_err());
+    }
+
+    /// Returns the resources section of a module bundling one file
+    /// with `builder`.
+    fn section(builder: &mut BundleBuilder) -> Vec<u8> {
+        let mut module = Vec::new();
+        builder
+            .add_bytes("file", "contents ".repeat(100))
+            .write(EMPTY_MODULE, &mut module)
+            .unwrap();
+        let (_, data) = find_section(RESOURCES_SECTION, &module).unwrap().unwrap();
+        data.to_vec()
+    }
+
+    #[test]
+    fn runtime_decode() {
+        for compression in COMPRESSIONS {
+            let data = section(BundleBuilder::new().compression(*compression));
+            let resources = Resources::decode(&data).unwrap();
+            assert_eq!(
+                resources.open("file"),
+                Ok("contents ".repeat(100).as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn runtime_decrypt() {
+        let key = [3; 32];
+        for cipher in &[Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let data = section(
+                BundleBuilder::new()
+                    .compression(Compression::Zstd)
+                    .encrypt(*cipher, EncryptionKey::new(key)),
+            );
+            assert!(Resources::decode(&data).unwrap().open("file").is_err());
+            let resources = Resources::decrypt(&data, &key).unwrap();
+            assert_eq!(
+                resources.open("file"),
+                Ok("contents ".repeat(100).as_bytes())
+            );
+            assert!(Resources::decrypt(&data, &[4; 32]).is_err());
+        }
+    }
+}
@@ -0,0 +1,372 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::{BundleError, Result};
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::str::FromStr;
+use wasmparser::{
+    ComponentAlias, ComponentExternalKind, ComponentName, ComponentOuterAliasKind,
+    ComponentTypeRef, KnownCustom, Parser, Payload::*,
+};
+
+/// Which module the resources are bundled into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// The top-level module or component.
+    TopLevel,
+    /// The core module nested in a component with the given index,
+    /// counting the modules defined at any depth in the order they
+    /// appear.
+    Index(usize),
+    /// The core module nested in a component with the given name in
+    /// the `component-name` section of its component.
+    Name(String),
+}
+
+impl FromStr for Target {
+    type Err = BundleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.parse() {
+            Ok(index) => Target::Index(index),
+            Err(_) => Target::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::TopLevel => write!(f, "top-level module"),
+            Target::Index(index) => write!(f, "module {}", index),
+            Target::Name(name) => write!(f, "module {}", name),
+        }
+    }
+}
+
+const COMPONENT_SECTION: u8 = 4;
+
+/// A section holding a nested module or component.
+#[derive(Clone)]
+struct Enclosing {
+    id: u8,
+    /// Where the section starts, and where its contents start and end.
+    start: usize,
+    contents: Range<usize>,
+}
+
+/// Returns where the section whose contents start at `contents` starts,
+/// by walking back over its size.
+fn section_start(input: &[u8], contents: usize) -> usize {
+    let mut start = contents - 1;
+    while start > 0 && input[start - 1] & 0x80 != 0 {
+        start -= 1;
+    }
+    start - 1
+}
+
+/// The sections enclosing a nested core module, outermost first, with
+/// the module's own section last.
+pub(crate) struct Nested(Vec<Enclosing>);
+
+/// What the core module index space of a component is made of so far.
+#[derive(Default)]
+struct Component {
+    /// The number of core modules in the index space.
+    modules: u32,
+    /// The index of each module defined in the component, along with
+    /// its index among all modules.
+    defined: Vec<(u32, usize)>,
+}
+
+/// Finds the nested core module `target` refers to in `input`.
+pub(crate) fn locate(input: &[u8], target: &Target) -> Result<Nested> {
+    let mut sections: Vec<Enclosing> = Vec::new();
+    let mut components: Vec<Component> = Vec::new();
+    let mut modules: Vec<Vec<Enclosing>> = Vec::new();
+    let mut named = None;
+    // Whether each parser in turn is for a component.
+    let mut stack: Vec<bool> = Vec::new();
+
+    if *target == Target::TopLevel {
+        return Ok(Nested(Vec::new()));
+    }
+
+    for payload in Parser::new(0).parse_all(input) {
+        match payload? {
+            Version { encoding, .. } if stack.is_empty() => {
+                if encoding == wasmparser::Encoding::Component {
+                    components.push(Component::default());
+                }
+                stack.push(encoding == wasmparser::Encoding::Component);
+            }
+            ModuleSection {
+                unchecked_range, ..
+            }
+            | ComponentSection {
+                unchecked_range, ..
+            } => {
+                let contents = unchecked_range.start as usize..unchecked_range.end as usize;
+                if contents.end > input.len() {
+                    return Err(BundleError::Parse {
+                        offset: unchecked_range.start,
+                        message: "unexpected end of module".to_string(),
+                    });
+                }
+                let start = section_start(input, contents.start);
+                let id = input[start];
+                sections.push(Enclosing {
+                    id,
+                    start,
+                    contents,
+                });
+
+                if id == COMPONENT_SECTION {
+                    components.push(Component::default());
+                    stack.push(true);
+                } else {
+                    let component = components.last_mut().unwrap();
+                    component.defined.push((component.modules, modules.len()));
+                    component.modules += 1;
+                    modules.push(sections.clone());
+                    stack.push(false);
+                }
+            }
+            ComponentImportSection(reader) => {
+                for import in reader {
+                    if let ComponentTypeRef::Module(_) = import?.ty {
+                        components.last_mut().unwrap().modules += 1;
+                    }
+                }
+            }
+            ComponentAliasSection(reader) => {
+                for alias in reader {
+                    match alias? {
+                        ComponentAlias::InstanceExport {
+                            kind: ComponentExternalKind::Module,
+                            ..
+                        }
+                        | ComponentAlias::Outer {
+                            kind: ComponentOuterAliasKind::CoreModule,
+                            ..
+                        } => components.last_mut().unwrap().modules += 1,
+                        _ => {}
+                    }
+                }
+            }
+            CustomSection(reader) => {
+                let target = match target {
+                    Target::Name(name) => name,
+                    _ => continue,
+                };
+                let names = match reader.as_known() {
+                    KnownCustom::ComponentName(names) => names,
+                    _ => continue,
+                };
+                let component = match components.last() {
+                    Some(component) if stack.last() == Some(&true) => component,
+                    _ => continue,
+                };
+
+                for name in names {
+                    if let ComponentName::CoreModules(map) = name? {
+                        for naming in map {
+                            let naming = naming?;
+                            if naming.name != target || named.is_some() {
+                                continue;
+                            }
+                            named = component
+                                .defined
+                                .iter()
+                                .find(|(index, _)| *index == naming.index)
+                                .map(|(_, module)| *module);
+                        }
+                    }
+                }
+            }
+            End(_) => {
+                if stack.pop() == Some(true) {
+                    components.pop();
+                }
+                if !stack.is_empty() {
+                    sections.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let module = match target {
+        Target::Index(index) => Some(*index),
+        _ => named,
+    };
+    match module.and_then(|module| modules.get(module)) {
+        Some(sections) => Ok(Nested(sections.clone())),
+        None => Err(BundleError::ModuleNotFound(target.clone())),
+    }
+}
+
+/// Looks up the module `target` refers to in `input`, returning its
+/// offset along with the module.
+pub fn find_module<'a>(input: &'a [u8], target: &Target) -> Result<(usize, &'a [u8])> {
+    Ok(locate(input, target)?.module(input))
+}
+
+impl Nested {
+    /// Returns the offset of the module in `input`, along with the
+    /// module.
+    pub(crate) fn module<'a>(&self, input: &'a [u8]) -> (usize, &'a [u8]) {
+        match self.0.last() {
+            Some(section) => (section.contents.start, &input[section.contents.clone()]),
+            None => (0, input),
+        }
+    }
+
+    /// Writes `input` to `output` with the module replaced by `module`,
+    /// adjusting the sizes of the sections enclosing it.
+    pub(crate) fn replace(
+        &self,
+        input: &[u8],
+        module: &[u8],
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        // Work out the new sizes from the innermost section out.
+        let mut sizes = vec![0; self.0.len()];
+        let mut size = module.len();
+        for (i, section) in self.0.iter().enumerate().rev() {
+            sizes[i] = size;
+            let mut header = Vec::new();
+            leb128::write::unsigned(&mut header, size as u64)?;
+            size += 1 + header.len();
+            if i > 0 {
+                let parent = &self.0[i - 1];
+                size += (section.start - parent.contents.start)
+                    + (parent.contents.end - section.contents.end);
+            }
+        }
+
+        let mut position = 0;
+        for (section, size) in self.0.iter().zip(sizes.iter()) {
+            output.write_all(&input[position..section.start])?;
+            output.write_all(&[section.id])?;
+            leb128::write::unsigned(output, *size as u64)?;
+            position = section.contents.start;
+        }
+        output.write_all(module)?;
+
+        // What follows the module in each enclosing section is all
+        // contiguous.
+        if let Some(section) = self.0.last() {
+            position = section.contents.end;
+        }
+        output.write_all(&input[position..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::write_section;
+    use wasmparser::{Validator, WasmFeatures};
+
+    /// Modules nested two components deep, behind an imported module
+    /// and an aliased one, told apart by the size of their memory.
+    const COMPONENT: &str = r#"
+        (component
+          (import "imported" (core module))
+          (core module (memory 0))
+          (component
+            (core module $outer)
+            (alias outer 1 0 (core module))
+            (component
+              (core module $inner (memory 1))
+            )
+            (core module $last (memory 2))
+          )
+          (core module $top (memory 3))
+        )
+    "#;
+
+    /// Returns the initial size of the memory of `module`, if any.
+    fn memory(module: &[u8]) -> Option<u64> {
+        for payload in Parser::new(0).parse_all(module) {
+            if let MemorySection(reader) = payload.unwrap() {
+                return Some(reader.into_iter().next()?.unwrap().initial);
+            }
+        }
+        None
+    }
+
+    fn find(input: &[u8], target: &str) -> Result<Option<u64>> {
+        find_module(input, &target.parse()?).map(|(_, module)| memory(module))
+    }
+
+    #[test]
+    fn find_by_index() {
+        let component = wat::parse_str(COMPONENT).unwrap();
+        assert_eq!(find(&component, "0").unwrap(), Some(0));
+        assert_eq!(find(&component, "1").unwrap(), None);
+        assert_eq!(find(&component, "2").unwrap(), Some(1));
+        assert_eq!(find(&component, "3").unwrap(), Some(2));
+        assert_eq!(find(&component, "4").unwrap(), Some(3));
+        assert!(matches!(
+            find(&component, "5"),
+            Err(BundleError::ModuleNotFound(Target::Index(5)))
+        ));
+    }
+
+    #[test]
+    fn find_by_name() {
+        let component = wat::parse_str(COMPONENT).unwrap();
+        assert_eq!(find(&component, "inner").unwrap(), Some(1));
+        // The imported and aliased modules come first in the index
+        // spaces of their components.
+        assert_eq!(find(&component, "last").unwrap(), Some(2));
+        assert_eq!(find(&component, "top").unwrap(), Some(3));
+        assert!(matches!(
+            find(&component, "missing"),
+            Err(BundleError::ModuleNotFound(Target::Name(_)))
+        ));
+    }
+
+    #[test]
+    fn replace_nested() {
+        let component = wat::parse_str(COMPONENT).unwrap();
+        let nested = locate(&component, &Target::Index(2)).unwrap();
+        assert_eq!(nested.0.len(), 3);
+        let (_, module) = nested.module(&component);
+        let sizes: Vec<_> = nested
+            .0
+            .iter()
+            .map(|section| section.contents.len())
+            .collect();
+        assert!(sizes[0] >= 128 && sizes[1] < 128 && sizes[2] < 128);
+
+        // Make the inner sections too large for a one-byte size too.
+        let mut grown = module.to_vec();
+        write_section("padding", &[0; 200], &mut grown).unwrap();
+        let mut output = Vec::new();
+        nested.replace(&component, &grown, &mut output).unwrap();
+        assert_eq!(
+            output.len(),
+            component.len() + grown.len() - module.len() + 2
+        );
+
+        Validator::new_with_features(WasmFeatures::default())
+            .validate_all(&output)
+            .unwrap();
+        assert_eq!(
+            find_module(&output, &Target::Index(2)).unwrap().1,
+            &grown[..]
+        );
+        for (target, memory) in &[
+            ("0", Some(0)),
+            ("inner", Some(1)),
+            ("3", Some(2)),
+            ("top", Some(3)),
+        ] {
+            assert_eq!(find(&output, target).unwrap(), *memory);
+        }
+    }
+}
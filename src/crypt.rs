@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::{BundleError, PathContext, Result};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, Nonce, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Marks a resources section whose payload is encrypted.
+///
+/// Like the compression magic, it can't start a plain tar archive.  It
+/// is followed by a byte identifying the [`Cipher`], the ID of the key
+/// and the nonce, which are authenticated along with the payload, and
+/// then the payload itself: the archive, compressed if asked to, and
+/// encrypted.
+const MAGIC: &[u8] = b"\0wbcrypt";
+const KEY_ID_SIZE: usize = 8;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + KEY_ID_SIZE + NONCE_SIZE;
+
+/// Authenticated encryption applied to the bundled archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter Mode.
+    Aes256Gcm,
+    /// ChaCha20 with Poly1305, for hosts without AES instructions.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = BundleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(BundleError::InvalidOption {
+                name: "cipher",
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// A 256-bit key the bundled archive is encrypted with.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Wraps the raw bytes of a key.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Returns the ID recorded in the section header, derived from the
+    /// key so that it needn't be managed separately.
+    pub fn id(&self) -> [u8; KEY_ID_SIZE] {
+        let digest = Sha256::new()
+            .chain_update(b"wasm-bundle key ID\0")
+            .chain_update(self.0)
+            .finalize();
+        let mut id = [0; KEY_ID_SIZE];
+        id.copy_from_slice(&digest[..KEY_ID_SIZE]);
+        id
+    }
+}
+
+// Keep the key itself out of logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({})", hex(&self.id()))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(digits: &[u8]) -> Option<[u8; 32]> {
+    let mut key = [0; 32];
+    if digits.len() != key.len() * 2 {
+        return None;
+    }
+    for (byte, digits) in key.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Reads a key from the file at `path`, holding either 32 raw bytes or
+/// 64 hexadecimal digits, as written by `openssl rand -hex 32`.
+pub fn read_encryption_key(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).with_path(path)?;
+
+    if let Some(key) = from_hex(bytes.trim_ascii()) {
+        return Ok(EncryptionKey(key));
+    }
+    if let Ok(key) = <[u8; 32]>::try_from(bytes.as_slice()) {
+        return Ok(EncryptionKey(key));
+    }
+
+    Err(BundleError::InvalidKey {
+        path: path.to_path_buf(),
+        message: "expected 32 bytes or 64 hexadecimal digits".to_string(),
+    })
+}
+
+fn seal<C: Aead + KeyInit>(
+    key: &EncryptionKey,
+    header: &[u8],
+    payload: &[u8],
+) -> io::Result<Vec<u8>> {
+    let nonce = Nonce::<C>::from_slice(&header[HEADER_SIZE - NONCE_SIZE..]);
+    C::new_from_slice(&key.0)
+        .map_err(|_| io::Error::from(ErrorKind::InvalidInput))?
+        .encrypt(
+            nonce,
+            Payload {
+                msg: payload,
+                aad: header,
+            },
+        )
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "payload too large to encrypt"))
+}
+
+fn open<C: Aead + KeyInit>(key: &EncryptionKey, header: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    let nonce = Nonce::<C>::from_slice(&header[HEADER_SIZE - NONCE_SIZE..]);
+    C::new_from_slice(&key.0)
+        .ok()?
+        .decrypt(
+            nonce,
+            Payload {
+                msg: payload,
+                aad: header,
+            },
+        )
+        .ok()
+}
+
+/// Encrypts `payload` with `key`, prefixed with the header identifying
+/// the cipher, key and nonce.
+///
+/// The nonce is random, so encrypted bundles differ from one run to the
+/// next even when reproducible.
+pub(crate) fn encrypt(cipher: Cipher, key: &EncryptionKey, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut header = MAGIC.to_vec();
+    header.push(cipher.id());
+    header.extend_from_slice(&key.id());
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    header.extend_from_slice(&nonce);
+
+    let encrypted = match cipher {
+        Cipher::Aes256Gcm => seal::<Aes256Gcm>(key, &header, payload)?,
+        Cipher::ChaCha20Poly1305 => seal::<ChaCha20Poly1305>(key, &header, payload)?,
+    };
+    header.extend_from_slice(&encrypted);
+    Ok(header)
+}
+
+/// Decrypts the contents of the resources section `section` with `key`,
+/// returning `None` if they aren't encrypted.
+pub(crate) fn decrypt(
+    section: &str,
+    data: &[u8],
+    key: Option<&EncryptionKey>,
+) -> Result<Option<Vec<u8>>> {
+    if !data.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let error = |message: String| BundleError::Decrypt {
+        section: section.to_string(),
+        message,
+    };
+    if data.len() < HEADER_SIZE {
+        return Err(BundleError::invalid_archive(
+            section,
+            ErrorKind::UnexpectedEof.into(),
+        ));
+    }
+
+    let (header, payload) = data.split_at(HEADER_SIZE);
+    let cipher = Cipher::from_id(header[MAGIC.len()])
+        .ok_or_else(|| error(format!("unknown cipher {}", header[MAGIC.len()])))?;
+    let id = &header[MAGIC.len() + 1..MAGIC.len() + 1 + KEY_ID_SIZE];
+    let key = match key {
+        Some(key) if key.id() == id => key,
+        Some(key) => {
+            return Err(error(format!(
+                "encrypted with key {}, not {}",
+                hex(id),
+                hex(&key.id())
+            )))
+        }
+        None => return Err(error(format!("encrypted with key {}", hex(id)))),
+    };
+
+    let decrypted = match cipher {
+        Cipher::Aes256Gcm => open::<Aes256Gcm>(key, header, payload),
+        Cipher::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(key, header, payload),
+    };
+    decrypted
+        .map(Some)
+        .ok_or_else(|| error("authentication failed".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RESOURCES_SECTION as SECTION;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let key = EncryptionKey::new([1; 32]);
+        for cipher in &[Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let encrypted = encrypt(*cipher, &key, b"payload").unwrap();
+            assert!(encrypted.starts_with(MAGIC));
+            assert_eq!(encrypted[MAGIC.len()], cipher.id());
+            let decrypted = decrypt(SECTION, &encrypted, Some(&key)).unwrap();
+            assert_eq!(decrypted.as_deref(), Some(&b"payload"[..]));
+        }
+    }
+
+    #[test]
+    fn decrypt_plain() {
+        let key = EncryptionKey::new([1; 32]);
+        assert_eq!(decrypt(SECTION, b"payload", Some(&key)).unwrap(), None);
+        assert_eq!(decrypt(SECTION, b"payload", None).unwrap(), None);
+    }
+
+    #[test]
+    fn decrypt_wrong_key() {
+        let key = EncryptionKey::new([1; 32]);
+        let other = EncryptionKey::new([2; 32]);
+        let encrypted = encrypt(Cipher::Aes256Gcm, &key, b"payload").unwrap();
+
+        for key in &[Some(&other), None] {
+            assert!(matches!(
+                decrypt(SECTION, &encrypted, *key),
+                Err(BundleError::Decrypt { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn decrypt_tampered() {
+        let key = EncryptionKey::new([1; 32]);
+        let encrypted = encrypt(Cipher::ChaCha20Poly1305, &key, b"payload").unwrap();
+
+        // Both the header, as associated data, and the payload are
+        // authenticated.
+        for i in &[HEADER_SIZE - 1, HEADER_SIZE, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[*i] ^= 1;
+            assert!(matches!(
+                decrypt(SECTION, &tampered, Some(&key)),
+                Err(BundleError::Decrypt { message, .. }) if message == "authentication failed"
+            ));
+        }
+        assert!(matches!(
+            decrypt(SECTION, &encrypted[..HEADER_SIZE - 1], Some(&key)),
+            Err(BundleError::InvalidArchive { .. })
+        ));
+    }
+
+    #[test]
+    fn read_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        std::fs::write(&path, "01".repeat(32) + "\n").unwrap();
+        assert_eq!(read_encryption_key(&path).unwrap().0, [1; 32]);
+        std::fs::write(&path, [2; 32]).unwrap();
+        assert_eq!(read_encryption_key(&path).unwrap().0, [2; 32]);
+        std::fs::write(&path, [2; 31]).unwrap();
+        assert!(matches!(
+            read_encryption_key(&path),
+            Err(BundleError::InvalidKey { .. })
+        ));
+    }
+}
@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::archive::{collect_items, hash_file, Entry, Item, Options};
+use crate::codec::open_archive;
+use crate::crypt::EncryptionKey;
+use crate::error::{BundleError, Result};
+use crate::reader::ResourceReader;
+use crate::section::find_unique_section;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+/// Appended to the name of the resources section to name the section
+/// holding the digests of the bundled files.
+pub(crate) const DIGEST_SUFFIX: &str = ".digest";
+
+/// Returns the name of the section holding the digests of the files
+/// bundled in the resources section `section`.
+pub fn digest_section(section: &str) -> String {
+    format!("{}{}", section, DIGEST_SUFFIX)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Cow<'_, Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(Path::new(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Cow<'_, Path> {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(path) => Cow::Borrowed(Path::new(path)),
+        Cow::Owned(path) => Cow::Owned(path.into()),
+    }
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+/// Undoes the escaping of a file name in a line flagged with a leading
+/// backslash, as `sha256sum -c` does.
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => match bytes.next()? {
+                b'\\' => result.push(b'\\'),
+                b'n' => result.push(b'\n'),
+                _ => return None,
+            },
+            _ => result.push(*byte),
+        }
+    }
+
+    Some(result)
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "malformed digest list")
+}
+
+/// How one set of files differs from another, by the digests of their
+/// contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// The files only in the other set.
+    pub added: Vec<PathBuf>,
+    /// The files only in this set.
+    pub removed: Vec<PathBuf>,
+    /// The files in both sets, with different contents.
+    pub modified: Vec<PathBuf>,
+}
+
+impl Changes {
+    /// Returns the number of files that differ.
+    pub fn len(&self) -> usize {
+        self.added.len() + self.removed.len() + self.modified.len()
+    }
+
+    /// Returns whether the sets are the same.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The SHA-256 digests of the contents of the files in a bundle.
+///
+/// They are stored as the lines `sha256sum` prints, sorted by path and
+/// with the same escaping of names holding a backslash or newline, so
+/// the files can be checked with `sha256sum -c` once extracted.  Hard
+/// links are listed with the digest of their target; directories and
+/// symbolic links are not listed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Digests(BTreeMap<Vec<u8>, [u8; 32]>);
+
+impl Digests {
+    /// Computes the digests of the files in the tar archive read from
+    /// `reader`.
+    pub(crate) fn from_archive(reader: impl Read) -> io::Result<Self> {
+        let mut digests = BTreeMap::new();
+
+        for entry in tar::Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            let name = entry.path_bytes().into_owned();
+
+            // Later entries replace earlier ones with the same path.
+            let kind = entry.header().entry_type();
+            let digest = if kind.is_file() {
+                let mut hasher = Sha256::new();
+                io::copy(&mut entry, &mut hasher)?;
+                Some(hasher.finalize().into())
+            } else if kind.is_hard_link() {
+                entry
+                    .link_name_bytes()
+                    .and_then(|target| digests.get(target.as_ref()).copied())
+            } else {
+                None
+            };
+            match digest {
+                Some(digest) => digests.insert(name, digest),
+                None => digests.remove(&name),
+            };
+        }
+
+        Ok(Self(digests))
+    }
+
+    /// Computes the digests of the files read by `reader`, following
+    /// links, so that a link compares equal to a copy of its target.
+    pub fn from_resources(reader: &ResourceReader<'_>) -> Self {
+        let mut digests = BTreeMap::new();
+        // Links share the contents of their targets, which are hashed
+        // once, keyed by where those contents are.
+        let mut hashed = HashMap::new();
+
+        for resource in reader.iter() {
+            let kind = resource.header().entry_type();
+            let file = if kind.is_file() {
+                Some(resource)
+            } else if kind.is_hard_link() || kind.is_symlink() {
+                reader
+                    .get(resource.path())
+                    .filter(|file| file.header().entry_type().is_file())
+            } else {
+                None
+            };
+
+            // Later entries replace earlier ones with the same path.
+            let name = path_bytes(resource.path()).into_owned();
+            match file {
+                Some(file) => {
+                    let data = file.data();
+                    let digest = *hashed
+                        .entry((data.as_ptr(), data.len()))
+                        .or_insert_with(|| Sha256::digest(data).into());
+                    digests.insert(name, digest)
+                }
+                None => digests.remove(&name),
+            };
+        }
+
+        Self(digests)
+    }
+
+    /// Computes the digests of the files `entries` describe, hashing them
+    /// as directories are walked and following symbolic links.
+    pub(crate) fn from_entries(entries: &[Entry]) -> Result<Self> {
+        let options = Options {
+            mtime: None,
+            links: false,
+            dedup: false,
+        };
+        let mut digests = BTreeMap::new();
+
+        // Later entries replace earlier ones with the same path.
+        for (name, item) in collect_items(entries, &options)? {
+            let name = path_bytes(&name).into_owned();
+            let digest = match item {
+                Item::File(path) => hash_file(&path)?,
+                Item::Bytes(data) => Sha256::digest(data).into(),
+                Item::Directory(_) | Item::Symlink(..) => {
+                    digests.remove(&name);
+                    continue;
+                }
+            };
+            digests.insert(name, digest);
+        }
+
+        Ok(Self(digests))
+    }
+
+    /// Returns how the files listed in `other` differ from these.
+    pub fn changes(&self, other: &Digests) -> Changes {
+        let mut changes = Changes::default();
+
+        for (name, digest) in &self.0 {
+            let path = path_from_bytes(name).into_owned();
+            match other.0.get(name) {
+                None => changes.removed.push(path),
+                Some(other) if other != digest => changes.modified.push(path),
+                Some(_) => {}
+            }
+        }
+        for name in other.0.keys() {
+            if !self.0.contains_key(name) {
+                changes.added.push(path_from_bytes(name).into_owned());
+            }
+        }
+
+        changes
+    }
+
+    /// Parses the contents of a digest section.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let mut digests = BTreeMap::new();
+
+        for line in data.split(|byte| *byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            if line.len() < 66 || &line[64..66] != b"  " {
+                return Err(malformed());
+            }
+            let mut digest = [0; 32];
+            for (i, byte) in digest.iter_mut().enumerate() {
+                let hex = std::str::from_utf8(&line[i * 2..i * 2 + 2]).map_err(|_| malformed())?;
+                *byte = u8::from_str_radix(hex, 16).map_err(|_| malformed())?;
+            }
+            let name = if escaped {
+                unescape(&line[66..]).ok_or_else(malformed)?
+            } else {
+                line[66..].to_vec()
+            };
+            digests.insert(name, digest);
+        }
+
+        Ok(Self(digests))
+    }
+
+    /// Returns the contents of the digest section.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (name, digest) in &self.0 {
+            // Like sha256sum, flag names that need escaping with a
+            // leading backslash.
+            let escaped = name.iter().any(|byte| matches!(byte, b'\\' | b'\n'));
+            if escaped {
+                bytes.push(b'\\');
+            }
+            for byte in digest {
+                bytes.extend_from_slice(format!("{:02x}", byte).as_bytes());
+            }
+            bytes.extend_from_slice(b"  ");
+            for byte in name {
+                match byte {
+                    b'\\' if escaped => bytes.extend_from_slice(b"\\\\"),
+                    b'\n' => bytes.extend_from_slice(b"\\n"),
+                    _ => bytes.push(*byte),
+                }
+            }
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    /// Returns the digest covering the whole bundle, that is the
+    /// SHA-256 of the digest section.
+    pub fn root(&self) -> [u8; 32] {
+        Sha256::digest(self.to_bytes()).into()
+    }
+
+    /// Returns the digest of the file at `path`, if listed.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&[u8; 32]> {
+        self.0.get(path_bytes(path.as_ref()).as_ref())
+    }
+
+    /// Iterates over the files and their digests, sorted by path.
+    pub fn iter(&self) -> impl Iterator<Item = (Cow<'_, Path>, &[u8; 32])> {
+        self.0
+            .iter()
+            .map(|(name, digest)| (path_from_bytes(name), digest))
+    }
+
+    /// Returns the number of files listed.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether no files are listed.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Checks the digests recorded for the resources section `section` in
+/// the module `input` against the bundled files, decrypted with `key`
+/// if they are encrypted, returning them.
+pub fn verify_digests(section: &str, input: &[u8], key: Option<&EncryptionKey>) -> Result<Digests> {
+    let name = digest_section(section);
+    let (_, recorded) = find_unique_section(&name, input)?
+        .ok_or_else(|| BundleError::SectionNotFound(name.clone()))?;
+    let recorded = Digests::parse(recorded).map_err(|e| BundleError::invalid_archive(&name, e))?;
+
+    let (_, data) = find_unique_section(section, input)?
+        .ok_or_else(|| BundleError::SectionNotFound(section.to_string()))?;
+    let archive = open_archive(section, data, key)?;
+    let actual = Digests::from_archive(archive.as_ref())
+        .map_err(|e| BundleError::invalid_archive(section, e))?;
+
+    let names = recorded.0.keys().chain(actual.0.keys());
+    for name in names {
+        if recorded.0.get(name) != actual.0.get(name) {
+            return Err(BundleError::DigestMismatch(
+                path_from_bytes(name).into_owned(),
+            ));
+        }
+    }
+
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::bundle_module;
+
+    #[test]
+    fn from_resources_follows_links() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in &[("a", "old"), ("a", "new"), ("b", "b")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        for (kind, path, target) in &[
+            (tar::EntryType::Link, "hard", "a"),
+            (tar::EntryType::Symlink, "soft", "b"),
+            (tar::EntryType::Symlink, "b", "a"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let module = bundle_module(&archive);
+
+        let digests = Digests::from_resources(&ResourceReader::from_wasm(&module).unwrap());
+        let new = Sha256::digest(b"new").into();
+        assert_eq!(digests.len(), 4);
+        for path in &["a", "hard", "soft", "b"] {
+            assert_eq!(digests.get(path), Some(&new));
+        }
+
+        // Only the contents of files and hard links are in the archive.
+        let changes = Digests::from_archive(archive.as_slice())
+            .unwrap()
+            .changes(&digests);
+        assert_eq!(
+            changes.added,
+            vec![PathBuf::from("b"), PathBuf::from("soft")]
+        );
+        assert!(changes.removed.is_empty() && changes.modified.is_empty());
+    }
+
+    #[test]
+    fn escaped_names() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in &["plain", "new\nline", "back\\slash"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            builder.append_data(&mut header, path, &b"x"[..]).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let digests = Digests::from_archive(archive.as_slice()).unwrap();
+
+        let hex = "2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881";
+        let expected = format!(
+            "\\{hex}  back\\\\slash\n\\{hex}  new\\nline\n{hex}  plain\n",
+            hex = hex
+        );
+        assert_eq!(String::from_utf8(digests.to_bytes()).unwrap(), expected);
+        assert_eq!(Digests::parse(expected.as_bytes()).unwrap(), digests);
+        assert!(digests.get("new\nline").is_some());
+
+        let malformed = format!("\\{}  bad\\escape\n", hex);
+        assert!(Digests::parse(malformed.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::component::Target;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Errors returned while bundling or reading resources.
+#[derive(Debug)]
+pub enum BundleError {
+    /// An I/O error, while accessing `path` if known.
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// A file to bundle, or one of its ancestors, is neither a regular
+    /// file nor a directory.
+    SpecialFile(PathBuf),
+    /// A file to bundle doesn't start with the prefix to remove.
+    MissingPrefix { path: PathBuf, prefix: PathBuf },
+    /// A path in the bundle is not relative to its root.
+    InvalidName(PathBuf),
+    /// A path in the bundle would be extracted outside of the
+    /// destination directory.
+    UnsafePath(PathBuf),
+    /// A link points outside of the bundle.
+    UnsafeLink { path: PathBuf, target: PathBuf },
+    /// A glob pattern or ignore file couldn't be parsed.
+    InvalidPattern { pattern: String, message: String },
+    /// A manifest couldn't be parsed.
+    InvalidManifest {
+        path: Option<PathBuf>,
+        message: String,
+    },
+    /// An option has an unrecognized value.
+    InvalidOption { name: &'static str, value: String },
+    /// Two options can't be used together.
+    ConflictingOptions(&'static str, &'static str),
+    /// The Wasm module couldn't be parsed at the given offset.
+    Parse { offset: u64, message: String },
+    /// The Wasm module has no custom section of the given name.
+    SectionNotFound(String),
+    /// The Wasm module has more than one custom section of the given
+    /// name, where only one is expected.
+    DuplicateSection(String),
+    /// The Wasm component has no such nested module.
+    ModuleNotFound(Target),
+    /// The resources in the custom section couldn't be read.
+    InvalidArchive { section: String, source: io::Error },
+    /// A signing or verifying key couldn't be read.
+    InvalidKey { path: PathBuf, message: String },
+    /// The signature of the custom section is malformed or doesn't
+    /// match.
+    InvalidSignature(String),
+    /// A bundled file doesn't match the digest recorded for it.
+    DigestMismatch(PathBuf),
+    /// The resources in the custom section are encrypted and couldn't
+    /// be decrypted.
+    Decrypt { section: String, message: String },
+    /// The given number of files differ between the bundle and the
+    /// files at `path`.
+    Outdated { path: PathBuf, count: usize },
+}
+
+/// A specialized `Result` type for bundling operations.
+pub type Result<T> = std::result::Result<T, BundleError>;
+
+impl BundleError {
+    pub(crate) fn invalid_archive(section: &str, source: io::Error) -> Self {
+        BundleError::InvalidArchive {
+            section: section.to_string(),
+            source,
+        }
+    }
+
+    /// Returns the process exit code for the error, following the
+    /// conventions of `sysexits.h`.
+    pub fn exit_code(&self) -> i32 {
+        const EX_USAGE: i32 = 64;
+        const EX_DATAERR: i32 = 65;
+        const EX_NOINPUT: i32 = 66;
+        const EX_IOERR: i32 = 74;
+
+        match self {
+            BundleError::Io { source, .. } if source.kind() == io::ErrorKind::NotFound => {
+                EX_NOINPUT
+            }
+            BundleError::Io { .. } => EX_IOERR,
+            BundleError::SpecialFile(_) => EX_NOINPUT,
+            BundleError::MissingPrefix { .. }
+            | BundleError::InvalidName(_)
+            | BundleError::InvalidPattern { .. }
+            | BundleError::InvalidOption { .. }
+            | BundleError::ConflictingOptions(..) => EX_USAGE,
+            BundleError::UnsafePath(_)
+            | BundleError::UnsafeLink { .. }
+            | BundleError::InvalidManifest { .. }
+            | BundleError::Parse { .. }
+            | BundleError::SectionNotFound(_)
+            | BundleError::DuplicateSection(_)
+            | BundleError::ModuleNotFound(_)
+            | BundleError::InvalidArchive { .. }
+            | BundleError::InvalidKey { .. }
+            | BundleError::InvalidSignature(_)
+            | BundleError::DigestMismatch(_)
+            | BundleError::Decrypt { .. }
+            | BundleError::Outdated { .. } => EX_DATAERR,
+        }
+    }
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            BundleError::Io { path: None, source } => write!(f, "{}", source),
+            BundleError::SpecialFile(path) => {
+                write!(f, "{}: not a regular file or directory", path.display())
+            }
+            BundleError::MissingPrefix { path, prefix } => write!(
+                f,
+                "{}: doesn't start with prefix {}",
+                path.display(),
+                prefix.display()
+            ),
+            BundleError::InvalidName(path) => {
+                write!(f, "{}: not a valid path in the bundle", path.display())
+            }
+            BundleError::UnsafePath(path) => write!(
+                f,
+                "{}: refusing to extract outside of the destination",
+                path.display()
+            ),
+            BundleError::UnsafeLink { path, target } => write!(
+                f,
+                "{}: link to {} points outside of the bundle",
+                path.display(),
+                target.display()
+            ),
+            BundleError::InvalidPattern { pattern, message } => {
+                write!(f, "invalid pattern {}: {}", pattern, message)
+            }
+            BundleError::InvalidManifest {
+                path: Some(path),
+                message,
+            } => write!(f, "{}: invalid manifest: {}", path.display(), message),
+            BundleError::InvalidManifest {
+                path: None,
+                message,
+            } => write!(f, "invalid manifest: {}", message),
+            BundleError::InvalidOption { name, value } => {
+                write!(f, "invalid {}: {}", name, value)
+            }
+            BundleError::ConflictingOptions(first, second) => {
+                write!(f, "{} can't be combined with {}", first, second)
+            }
+            BundleError::Parse { offset, message } => {
+                write!(f, "invalid Wasm module at offset {}: {}", offset, message)
+            }
+            BundleError::SectionNotFound(section) => {
+                write!(f, "no {} section in the Wasm module", section)
+            }
+            BundleError::DuplicateSection(section) => {
+                write!(f, "more than one {} section in the Wasm module", section)
+            }
+            BundleError::ModuleNotFound(target) => write!(f, "no {} in the Wasm component", target),
+            BundleError::InvalidArchive { section, source } => {
+                write!(f, "invalid resources in {} section: {}", section, source)
+            }
+            BundleError::InvalidKey { path, message } => {
+                write!(f, "{}: invalid key: {}", path.display(), message)
+            }
+            BundleError::InvalidSignature(section) => {
+                write!(f, "signature of {} section doesn't verify", section)
+            }
+            BundleError::DigestMismatch(path) => {
+                write!(f, "{}: doesn't match the recorded digest", path.display())
+            }
+            BundleError::Outdated { path, count } => write!(
+                f,
+                "{}: {} files differ from the bundle",
+                path.display(),
+                count
+            ),
+            BundleError::Decrypt { section, message } => write!(
+                f,
+                "can't decrypt resources in {} section: {}",
+                section, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BundleError::Io { source, .. } | BundleError::InvalidArchive { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BundleError {
+    fn from(source: io::Error) -> Self {
+        BundleError::Io { path: None, source }
+    }
+}
+
+impl From<wasmparser::BinaryReaderError> for BundleError {
+    fn from(error: wasmparser::BinaryReaderError) -> Self {
+        BundleError::Parse {
+            offset: error.offset(),
+            message: error.message().to_string(),
+        }
+    }
+}
+
+impl From<BundleError> for io::Error {
+    fn from(error: BundleError) -> Self {
+        match error {
+            BundleError::Io { path: None, source } => source,
+            BundleError::Io { ref source, .. } => io::Error::new(source.kind(), error),
+            _ => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+/// Attaches the path being accessed to I/O errors.
+pub(crate) trait PathContext<T> {
+    fn with_path(self, path: &Path) -> Result<T>;
+}
+
+impl<T> PathContext<T> for io::Result<T> {
+    fn with_path(self, path: &Path) -> Result<T> {
+        self.map_err(|source| BundleError::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        })
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::{BundleError, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+
+fn build(patterns: &[impl AsRef<str>]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        let glob = Glob::new(pattern).map_err(|e| BundleError::InvalidPattern {
+            pattern: pattern.to_string(),
+            message: e.kind().to_string(),
+        })?;
+        builder.add(glob);
+    }
+
+    let set = builder.build().map_err(|e| BundleError::InvalidPattern {
+        pattern: e.glob().unwrap_or_default().to_string(),
+        message: e.kind().to_string(),
+    })?;
+    Ok(Some(set))
+}
+
+fn is_match(set: &GlobSet, path: &Path) -> bool {
+    set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name))
+}
+
+/// The names of the files listing patterns to skip while walking a
+/// directory, in the `.gitignore` format.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".bundleignore"];
+
+/// Reads the ignore files in `dir`, if any.
+pub(crate) fn read_ignore_files(dir: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+
+    for name in IGNORE_FILES {
+        let path = dir.join(name);
+        if path.is_file() {
+            if let Some(e) = builder.add(&path) {
+                return Err(ignore_error(&path, e));
+            }
+        }
+    }
+
+    builder.build().map_err(|e| ignore_error(dir, e))
+}
+
+fn ignore_error(path: &Path, error: ignore::Error) -> BundleError {
+    match error.into_io_error() {
+        Some(source) => BundleError::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        },
+        None => BundleError::InvalidPattern {
+            pattern: path.display().to_string(),
+            message: "invalid ignore file".to_string(),
+        },
+    }
+}
+
+/// Returns whether `path` is ignored by the ignore files read from its
+/// ancestors, innermost last.
+pub(crate) fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    for ignore in ignores.iter().rev() {
+        let matched = ignore.matched(path, is_dir);
+        if !matched.is_none() {
+            return matched.is_ignore();
+        }
+    }
+
+    false
+}
+
+/// Include and exclude patterns selecting the files bundled from a
+/// directory.
+///
+/// Patterns are matched against both the paths relative to the
+/// directory and the file names, so `*.map` excludes source maps and
+/// `.git` excludes Git directories at any depth.
+#[derive(Clone, Default)]
+pub struct Globs {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    ignore_files: bool,
+}
+
+impl Globs {
+    /// Selects the files matching any of `include`, or all files if it
+    /// is empty, that don't match any of `exclude`.
+    pub fn new(include: &[impl AsRef<str>], exclude: &[impl AsRef<str>]) -> Result<Self> {
+        Ok(Self {
+            include: build(include)?,
+            exclude: build(exclude)?,
+            ignore_files: false,
+        })
+    }
+
+    /// Also skips the files matched by `.gitignore` and `.bundleignore`
+    /// files found while walking the directory.
+    pub fn with_ignore_files(mut self) -> Self {
+        self.ignore_files = true;
+        self
+    }
+
+    pub(crate) fn ignore_files(&self) -> bool {
+        self.ignore_files
+    }
+
+    /// Returns whether the file at `path` is selected, excluding it
+    /// also if any of its ancestors match an exclude pattern.
+    pub fn matches(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.is_included(path)
+            && !path
+                .ancestors()
+                .skip(1)
+                .any(|ancestor| ancestor != Path::new("") && self.is_excluded(ancestor))
+    }
+
+    /// Returns whether `path` is excluded; excluded directories are not
+    /// walked.
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.as_ref().is_some_and(|set| is_match(set, path))
+    }
+
+    /// Returns whether the file at `path` is included.
+    pub(crate) fn is_included(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|set| is_match(set, path)) && !self.is_excluded(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excluded_ancestors() {
+        let globs = Globs::new(&["*.js"], &["node_modules", "build/tmp"]).unwrap();
+        assert!(globs.matches("app.js"));
+        assert!(globs.matches("src/app.js"));
+        assert!(!globs.matches("app.css"));
+        assert!(!globs.matches("node_modules/app.js"));
+        assert!(!globs.matches("src/node_modules/lib/app.js"));
+        assert!(!globs.matches("build/tmp/app.js"));
+        assert!(globs.matches("build/app.js"));
+        assert!(globs.matches("src/build/tmp/app.js"));
+    }
+}
@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bundle resource files into a WebAssembly module as a custom
+//! section.
+
+mod archive;
+mod builder;
+mod codec;
+mod component;
+mod crypt;
+mod digest;
+mod error;
+mod glob;
+mod manifest;
+mod reader;
+mod section;
+mod signature;
+mod validate;
+
+pub use archive::{extract, extract_with_key, Stats};
+pub use builder::BundleBuilder;
+pub use codec::Compression;
+pub use component::{find_module, Target};
+pub use crypt::{read_encryption_key, Cipher, EncryptionKey};
+pub use digest::{digest_section, verify_digests, Changes, Digests};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+pub use error::{BundleError, Result};
+pub use glob::Globs;
+pub use manifest::Manifest;
+pub use reader::{Resource, ResourceReader};
+pub use section::{filter, find_section, Position};
+pub use signature::{read_signing_key, read_verifying_key, signature_section, verify, Scope};
+pub use wasmparser::WasmFeatures;
+
+/// The name of the custom section resources are bundled into by default.
+pub const RESOURCES_SECTION: &str = ".enarx.resources";
@@ -1,12 +1,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::io::prelude::*;
-use std::io::{BufReader, ErrorKind, Read, Result, Write};
+use std::io::{BufReader, Read, Result, Write};
 use std::path::{Path, PathBuf};
-use wasmparser::{Chunk, Parser, Payload::*};
-
-const RESOURCES_SECTION: &str = ".enarx.resources";
+use wasm_bundle::{
+    digest_section, extract_with_key, find_module, find_section, read_encryption_key,
+    read_signing_key, read_verifying_key, verify, verify_digests, BundleBuilder, BundleError,
+    Digests, EncryptionKey, Globs, Manifest, ResourceReader, Scope, Target, WasmFeatures,
+    RESOURCES_SECTION,
+};
 
 fn read_paths(reader: &mut impl Read) -> Result<Vec<PathBuf>> {
     let mut reader = BufReader::new(reader);
@@ -26,109 +29,561 @@ fn read_paths(reader: &mut impl Read) -> Result<Vec<PathBuf>> {
     Ok(result)
 }
 
-fn create_archive(paths: Vec<PathBuf>, prefix: &str, writer: &mut impl Write) -> Result<()> {
-    let mut builder = tar::Builder::new(writer);
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(std::ffi::OsString::from_vec(bytes).into())
+}
 
-    for path in paths {
-        for ancestor in path.ancestors() {
-            if ancestor == Path::new("") {
-                break;
-            }
-            let metadata = std::fs::metadata(&ancestor)?;
-            if !metadata.is_dir() && !metadata.is_file() {
-                return Err(ErrorKind::InvalidInput.into());
-            }
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    let path = String::from_utf8(bytes).or(Err(std::io::ErrorKind::InvalidData))?;
+    Ok(path.into())
+}
+
+fn read_null_paths(reader: &mut impl Read) -> Result<Vec<PathBuf>> {
+    let mut reader = BufReader::new(reader);
+    let mut result: Vec<PathBuf> = Vec::new();
+
+    loop {
+        let mut buf = Vec::new();
+        let size = reader.read_until(0, &mut buf)?;
+        if size == 0 {
+            break;
+        }
+
+        // Unlike newline-separated lists, the path is taken verbatim.
+        if buf.last() == Some(&0) {
+            buf.pop();
         }
-        let name = path.strip_prefix(prefix).or(Err(ErrorKind::InvalidInput))?;
-        builder.append_path_with_name(&path, &name)?;
+        result.push(path_from_bytes(buf)?);
     }
 
-    builder.finish()?;
+    Ok(result)
+}
+
+fn format_mode(header: &tar::Header) -> Result<String> {
+    let kind = match header.entry_type() {
+        tar::EntryType::Directory => 'd',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Link => 'h',
+        _ => '-',
+    };
+    let mode = header.mode()?;
+    let mut result = String::with_capacity(10);
+
+    result.push(kind);
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        result.push(if mode & (0o400 >> i) != 0 { c } else { '-' });
+    }
+
+    Ok(result)
+}
+
+fn list(
+    section: &str,
+    target: &Target,
+    key: Option<&EncryptionKey>,
+    input: &[u8],
+    writer: &mut impl Write,
+) -> wasm_bundle::Result<()> {
+    let (base, module) = find_module(input, target)?;
+    let reader = ResourceReader::from_wasm_section_with_key(module, section, key)?;
+
+    for resource in reader.iter() {
+        let header = resource.header();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(header.mtime()?);
+        let link = match (header.entry_type(), resource.link_name()) {
+            (tar::EntryType::Symlink, Some(target)) => format!(" -> {}", target.display()),
+            (tar::EntryType::Link, Some(target)) => format!(" link to {}", target.display()),
+            _ => String::new(),
+        };
+
+        writeln!(
+            writer,
+            "{} {:>10} {} {:>10} {}{}",
+            format_mode(header)?,
+            resource.data().len(),
+            humantime::format_rfc3339_seconds(mtime),
+            match resource.offset() {
+                Some(offset) => (base + offset).to_string(),
+                None => "-".to_string(),
+            },
+            resource.path().display(),
+            link
+        )?;
+    }
 
     Ok(())
 }
 
-fn filter(section: &str, mut input: impl Read, output: &mut impl Write) -> Result<()> {
-    let mut buf = Vec::new();
-    let mut parser = Parser::new(0);
-    let mut eof = false;
-    let mut stack = Vec::new();
+/// The largest files whose changes are shown as text diffs.
+const MAX_DIFF_SIZE: usize = 64 * 1024;
 
-    loop {
-        let (payload, consumed) = match parser.parse(&buf, eof)
-            .or(Err(ErrorKind::InvalidInput))?
-        {
-            Chunk::NeedMoreData(hint) => {
-                assert!(!eof); // otherwise an error would be returned
-
-                // Use the hint to preallocate more space, then read
-                // some more data into our buffer.
-                //
-                // Note that the buffer management here is not ideal,
-                // but it's compact enough to fit in an example!
-                let len = buf.len();
-                buf.extend((0..hint).map(|_| 0u8));
-                let n = input.read(&mut buf[len..])?;
-                buf.truncate(len + n);
-                eof = n == 0;
-                continue;
-            }
+/// Returns `data` as text, if it is small and looks like text enough to
+/// be worth diffing.
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.len() > MAX_DIFF_SIZE || data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
 
-            Chunk::Parsed { consumed, payload } => (payload, consumed),
-        };
+/// Lists the files added, removed or modified between the bundles in
+/// `old` and `new`, with unified diffs of modified text files if asked
+/// to.
+fn diff(
+    section: &str,
+    target: &Target,
+    key: Option<&EncryptionKey>,
+    unified: bool,
+    (old, new): (&[u8], &[u8]),
+    writer: &mut impl Write,
+) -> wasm_bundle::Result<bool> {
+    let (_, old) = find_module(old, target)?;
+    let (_, new) = find_module(new, target)?;
+    let old = ResourceReader::from_wasm_section_with_key(old, section, key)?;
+    let new = ResourceReader::from_wasm_section_with_key(new, section, key)?;
+    let changes = Digests::from_resources(&old).changes(&Digests::from_resources(&new));
 
-        match payload {
-            CustomSection { name, .. } => {
-                if name != section {
-                    output.write_all(&buf[..consumed])?;
-                }
-            }
-            // When parsing nested modules we need to switch which
-            // `Parser` we're using.
-            ModuleCodeSectionEntry { parser: subparser, .. } => {
-                stack.push(parser);
-                parser = subparser;
-            }
-            End => {
-                if let Some(parent_parser) = stack.pop() {
-                    parser = parent_parser;
-                } else {
-                    break;
-                }
+    for path in &changes.added {
+        writeln!(writer, "added {}", path.display())?;
+    }
+    for path in &changes.removed {
+        writeln!(writer, "removed {}", path.display())?;
+    }
+    for path in &changes.modified {
+        writeln!(writer, "modified {}", path.display())?;
+        if !unified {
+            continue;
+        }
+
+        let old = old.get(path).and_then(|file| as_text(file.data()));
+        let new = new.get(path).and_then(|file| as_text(file.data()));
+        if let (Some(old), Some(new)) = (old, new) {
+            let diff = similar::TextDiff::from_lines(old, new);
+            write!(
+                writer,
+                "{}",
+                diff.unified_diff().header(
+                    &format!("a/{}", path.display()),
+                    &format!("b/{}", path.display())
+                )
+            )?;
+        }
+    }
+
+    Ok(!changes.is_empty())
+}
+
+/// Compares the files read by `reader` with those `sources` would
+/// bundle, printing the files missing from the sources, added to them
+/// or changed, and failing if there are any; `path` names the sources
+/// in the error.
+fn compare(
+    reader: &ResourceReader,
+    path: &str,
+    sources: &BundleBuilder,
+    writer: &mut impl Write,
+) -> wasm_bundle::Result<()> {
+    let changes = Digests::from_resources(reader).changes(&sources.file_digests()?);
+
+    for (status, paths) in [
+        ("missing", &changes.removed),
+        ("added", &changes.added),
+        ("changed", &changes.modified),
+    ] {
+        for path in paths {
+            writeln!(writer, "{} {}", status, path.display())?;
+        }
+    }
+
+    if !changes.is_empty() {
+        return Err(BundleError::Outdated {
+            path: path.into(),
+            count: changes.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks the signature of the resources, if a public key is given,
+/// the digests of the bundled files, if recorded or nothing else is
+/// checked, and the files against those `against` would bundle, if
+/// given along with where they come from.
+fn verify_module(
+    (input_path, input): (&str, &[u8]),
+    section: &str,
+    target: &Target,
+    pubkey: Option<&str>,
+    key: Option<&EncryptionKey>,
+    against: Option<(&str, &BundleBuilder)>,
+    writer: &mut impl Write,
+) -> wasm_bundle::Result<()> {
+    let (_, module) = find_module(input, target)?;
+
+    if let Some(pubkey) = pubkey {
+        let key = read_verifying_key(pubkey)?;
+        let scope = verify(section, module, &key)?;
+        writeln!(
+            writer,
+            "{}: signature of {} verified",
+            input_path,
+            match scope {
+                Scope::Resources => "resources",
+                Scope::Module => "resources and module",
             }
-            _ => {
-                output.write_all(&buf[..consumed])?;
+        )?;
+    }
+
+    let checked = pubkey.is_some() || against.is_some();
+    if !checked || find_section(&digest_section(section), module)?.is_some() {
+        let digests = verify_digests(section, module, key)?;
+        let root: String = digests
+            .root()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(
+            writer,
+            "{}: digests of {} files verified, root {}",
+            input_path,
+            digests.len(),
+            root
+        )?;
+    }
+
+    if let Some((path, sources)) = against {
+        let reader = ResourceReader::from_wasm_section_with_key(module, section, key)?;
+        compare(&reader, path, sources, writer)?;
+        writeln!(writer, "{}: bundled files match {}", input_path, path)?;
+    }
+
+    Ok(())
+}
+
+/// Looks up a Wasm proposal by its name in kebab case, such as
+/// `multi-memory`.
+fn feature_from_name(name: &str) -> wasm_bundle::Result<WasmFeatures> {
+    WasmFeatures::from_name(&name.replace('-', "_").to_uppercase()).ok_or_else(|| {
+        BundleError::InvalidOption {
+            name: "proposal",
+            value: name.to_string(),
+        }
+    })
+}
+
+/// Returns a function attaching `path` to an I/O error.
+fn with_path(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> BundleError {
+    move |source| BundleError::Io {
+        path: Some(path.as_ref().to_path_buf()),
+        source,
+    }
+}
+
+/// Standard output, where a reader going away, as `head` does, ends
+/// the output rather than failing the command.
+#[derive(Default)]
+struct Output {
+    closed: bool,
+}
+
+impl Output {
+    fn check(&mut self, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(())
             }
+            result => result,
         }
+    }
+}
 
-        // once we're done processing the payload we can forget the
-        // original.
-        buf.drain(..consumed);
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.closed {
+            let result = std::io::stdout().write_all(buf);
+            self.check(result)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = std::io::stdout().flush();
+        self.check(result)
+    }
+}
+
+fn section_arg() -> Arg<'static, 'static> {
+    Arg::with_name("section")
+        .help("Sets the section name")
+        .short("-j")
+        .long("section")
+        .takes_value(true)
+        .default_value(RESOURCES_SECTION)
+}
+
+fn prefix_arg() -> Arg<'static, 'static> {
+    Arg::with_name("prefix")
+        .help("Sets the path prefix to be removed")
+        .short("-p")
+        .long("prefix")
+        .takes_value(true)
+        .default_value("")
+}
+
+fn module_arg() -> Arg<'static, 'static> {
+    Arg::with_name("module")
+        .help("Targets the core module of a component with the given index or name")
+        .long("module")
+        .value_name("INDEX|NAME")
+        .takes_value(true)
+}
+
+fn key_arg() -> Arg<'static, 'static> {
+    Arg::with_name("key")
+        .help("Decrypts the bundled files with the key in the file")
+        .long("key")
+        .value_name("KEY")
+        .takes_value(true)
+}
+
+fn encryption_key(matches: &ArgMatches) -> wasm_bundle::Result<Option<EncryptionKey>> {
+    matches.value_of("key").map(read_encryption_key).transpose()
+}
+
+fn include_arg() -> Arg<'static, 'static> {
+    Arg::with_name("include")
+        .help("Only bundles files matching the glob pattern")
+        .long("include")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+fn exclude_arg() -> Arg<'static, 'static> {
+    Arg::with_name("exclude")
+        .help("Skips files and directories matching the glob pattern")
+        .long("exclude")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+fn ignore_files_arg() -> Arg<'static, 'static> {
+    Arg::with_name("ignore-files")
+        .help("Skips files matched by .gitignore and .bundleignore in directories")
+        .long("ignore-files")
+}
+
+fn globs(matches: &ArgMatches) -> wasm_bundle::Result<Globs> {
+    let include: Vec<&str> = matches.values_of("include").into_iter().flatten().collect();
+    let exclude: Vec<&str> = matches.values_of("exclude").into_iter().flatten().collect();
+    let globs = Globs::new(&include, &exclude)?;
+    if matches.is_present("ignore-files") {
+        Ok(globs.with_ignore_files())
+    } else {
+        Ok(globs)
+    }
+}
+
+/// Adds the file or directory at `path` to `builder`, as `name` or
+/// otherwise as its path without `prefix`.
+fn add_path(
+    builder: &mut BundleBuilder,
+    path: PathBuf,
+    name: Option<PathBuf>,
+    prefix: &str,
+    globs: &Globs,
+) -> wasm_bundle::Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => path
+            .strip_prefix(prefix)
+            .map_err(|_| BundleError::MissingPrefix {
+                path: path.clone(),
+                prefix: prefix.into(),
+            })?
+            .to_path_buf(),
+    };
+
+    // Directories are walked recursively, in sorted order, with
+    // the patterns matched relative to them
+    if path.is_dir() {
+        builder.add_directory_with_globs(path, name, globs.clone());
+    } else if globs.matches(&name) {
+        builder.add_file(path, name);
     }
     Ok(())
 }
 
-fn append(section: &str, mut archive: &std::fs::File, writer: &mut impl Write) -> Result<()> {
-    let mut header: Vec<u8> = Vec::new();
-    let name = section.as_bytes();
-    leb128::write::unsigned(&mut header, name.len() as u64)?;
-    header.write_all(name)?;
-    let size = archive.seek(std::io::SeekFrom::End(0))?;
+fn target(matches: &ArgMatches) -> wasm_bundle::Result<Target> {
+    match matches.value_of("module") {
+        Some(module) => module.parse(),
+        None => Ok(Target::TopLevel),
+    }
+}
 
-    writer.write_all(&[0])?;
-    leb128::write::unsigned(writer, size + header.len() as u64)?;
-    writer.write_all(&header)?;
+/// Splits a file given on the command line as SRC=DEST, unless a file
+/// by the name of the whole argument exists.  Sources can contain `=`
+/// too, in which case the first split naming an existing file is used.
+fn mapping(arg: &str) -> (PathBuf, Option<PathBuf>) {
+    let exists = |path: &str| std::fs::symlink_metadata(path).is_ok();
+    if exists(arg) {
+        return (arg.into(), None);
+    }
 
-    let _ = archive.seek(std::io::SeekFrom::Start(0))?;
-    loop {
-        let mut buf = [0; 4096];
-        let n = archive.read(&mut buf[..])?;
+    let mut splits = arg
+        .match_indices('=')
+        .map(|(i, _)| (&arg[..i], &arg[i + 1..]));
+    let first = splits.clone().next();
+    match splits.find(|(source, _)| exists(source)).or(first) {
+        Some((source, destination)) => (source.into(), Some(destination.into())),
+        None => (arg.into(), None),
+    }
+}
 
-        if n == 0 {
-            break;
+/// Returns the files given by `--against` and `manifest` to check the
+/// bundle against, along with where they come from.
+fn against<'a>(
+    matches: &'a ArgMatches,
+    manifest: Option<&Manifest>,
+) -> wasm_bundle::Result<Option<(&'a str, BundleBuilder)>> {
+    let mut builder = BundleBuilder::new();
+
+    if let Some(manifest) = manifest {
+        manifest.apply(&mut builder)?;
+    }
+    if let Some(dir) = matches.value_of("against") {
+        let prefix = matches.value_of("prefix").unwrap();
+        add_path(&mut builder, dir.into(), None, prefix, &globs(matches)?)?;
+    }
+
+    Ok(matches
+        .value_of("against")
+        .or_else(|| matches.value_of("manifest"))
+        .map(|path| (path, builder)))
+}
+
+fn bundle(matches: &ArgMatches) -> wasm_bundle::Result<()> {
+    let in_place = matches.is_present("in-place");
+    let input_path = matches.value_of("INPUT").unwrap();
+    let output_path = if in_place {
+        input_path
+    } else {
+        matches.value_of("OUTPUT").unwrap()
+    };
+
+    // Collect the files given on the command line, optionally mapped
+    // to a destination as SRC=DEST, or otherwise from the file list
+    // read.  With --in-place, there is no OUTPUT and the second
+    // argument is the first PATH.
+    let args: Vec<(PathBuf, Option<PathBuf>)> = matches
+        .values_of("OUTPUT")
+        .filter(|_| in_place)
+        .into_iter()
+        .flatten()
+        .chain(matches.values_of("PATH").into_iter().flatten())
+        .chain(matches.values_of("add").into_iter().flatten())
+        .map(mapping)
+        .collect();
+    let paths = if !args.is_empty() || matches.is_present("manifest") {
+        args
+    } else {
+        let mut reader = std::io::stdin();
+        if matches.is_present("null") {
+            read_null_paths(&mut reader)
+        } else {
+            read_paths(&mut reader)
+        }?
+        .into_iter()
+        .map(|path| (path, None))
+        .collect()
+    };
+    let mut builder = BundleBuilder::new();
+
+    if let Some(path) = matches.value_of("manifest") {
+        let manifest = Manifest::from_path(path)?;
+        manifest.apply(&mut builder)?;
+    }
+
+    let globs = globs(matches)?;
+    let prefix = matches.value_of("prefix").unwrap();
+    for (path, destination) in paths {
+        add_path(&mut builder, path, destination, prefix, &globs)?;
+    }
+
+    // Options given explicitly take precedence over the manifest
+    if matches.occurrences_of("section") > 0 {
+        builder.section(matches.value_of("section").unwrap());
+    }
+
+    if matches.occurrences_of("compress") > 0 {
+        let compression = matches.value_of("compress").unwrap();
+        builder.compression(compression.parse()?);
+    }
+
+    let position = matches.value_of("position").unwrap();
+    builder.position(position.parse()?);
+    builder.module(target(matches)?);
+
+    if matches.is_present("no-validate") {
+        builder.validate(None);
+    } else {
+        let mut features = WasmFeatures::default();
+        for name in matches.values_of("enable").into_iter().flatten() {
+            features.insert(feature_from_name(name)?);
+        }
+        for name in matches.values_of("disable").into_iter().flatten() {
+            features.remove(feature_from_name(name)?);
         }
+        builder.validate(Some(features));
+    }
+
+    builder.digests(matches.is_present("digests"));
 
-        writer.write_all(&buf[..n])?;
+    if let Some(path) = matches.value_of("encrypt") {
+        let cipher = matches.value_of("cipher").unwrap();
+        builder.encrypt(cipher.parse()?, read_encryption_key(path)?);
+    }
+
+    if let Some(path) = matches.value_of("sign") {
+        let scope = if matches.is_present("sign-module") {
+            Scope::Module
+        } else {
+            Scope::Resources
+        };
+        builder.sign(read_signing_key(path)?, scope);
+    }
+
+    builder.preserve_links(matches.is_present("preserve-links"));
+    let dedup = matches.is_present("dedup");
+    builder.deduplicate(dedup);
+
+    if matches.is_present("reproducible") {
+        // See https://reproducible-builds.org/specs/source-date-epoch/
+        let mtime = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(value) => value.parse().map_err(|_| BundleError::InvalidOption {
+                name: "SOURCE_DATE_EPOCH",
+                value,
+            })?,
+            Err(_) => 0,
+        };
+        builder.reproducible(mtime);
+    }
+
+    // Replace the existing .resources section with the files
+    let stats = builder.write_file(input_path, output_path)?;
+
+    if dedup {
+        eprintln!(
+            "wasm-bundle: stored {} of {} entries as links, saving {} bytes",
+            stats.links, stats.entries, stats.bytes_saved
+        );
     }
 
     Ok(())
@@ -137,6 +592,7 @@ fn append(section: &str, mut archive: &std::fs::File, writer: &mut impl Write) -
 fn main() {
     let matches = App::new("wasm-bundle")
         .about("Bundle resource files into a Wasm file")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("INPUT")
                 .help("Sets the input Wasm file")
@@ -146,46 +602,383 @@ fn main() {
         .arg(
             Arg::with_name("OUTPUT")
                 .help("Sets the output Wasm file")
-                .required(true)
+                .required_unless("in-place")
                 .index(2),
         )
         .arg(
-            Arg::with_name("prefix")
-                .help("Sets the path prefix to be removed")
-                .short("-p")
-                .long("prefix")
+            Arg::with_name("in-place")
+                .help("Rewrites the input Wasm file instead of writing an output file")
+                .short("-i")
+                .long("in-place"),
+        )
+        .arg(
+            Arg::with_name("PATH")
+                .help("Adds files or directories, optionally as SRC=DEST, instead of reading a file list")
+                .multiple(true)
+                .index(3),
+        )
+        .arg(
+            Arg::with_name("add")
+                .help("Adds a file or directory, optionally as SRC=DEST, instead of reading a file list")
+                .long("add")
                 .takes_value(true)
-                .default_value(""),
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .help("Reads the files to bundle from a manifest file")
+                .short("-m")
+                .long("manifest")
+                .takes_value(true),
         )
+        .arg(include_arg())
+        .arg(exclude_arg())
+        .arg(ignore_files_arg())
+        .arg(prefix_arg())
+        .arg(section_arg())
+        .arg(module_arg())
         .arg(
-            Arg::with_name("section")
-                .help("Sets the section name")
-                .short("-j")
-                .long("section")
+            Arg::with_name("null")
+                .help("Reads NUL-terminated paths, as printed by find -print0")
+                .short("-0")
+                .long("null"),
+        )
+        .arg(
+            Arg::with_name("position")
+                .help("Sets where the section is placed in the module")
+                .long("position")
                 .takes_value(true)
-                .default_value(RESOURCES_SECTION),
+                .possible_values(&["start", "after-type", "before-code", "end"])
+                .default_value("end"),
+        )
+        .arg(
+            Arg::with_name("preserve-links")
+                .help("Records symbolic links, and files with several names, as links")
+                .long("preserve-links"),
+        )
+        .arg(
+            Arg::with_name("dedup")
+                .help("Stores identical files once, and reports the bytes saved")
+                .long("dedup"),
+        )
+        .arg(
+            Arg::with_name("reproducible")
+                .help("Normalizes file metadata, using SOURCE_DATE_EPOCH as the time")
+                .long("reproducible"),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .help("Validates the input module and checks the output (default)")
+                .long("validate")
+                .overrides_with("no-validate"),
+        )
+        .arg(
+            Arg::with_name("no-validate")
+                .help("Skips validating the input and output modules")
+                .long("no-validate")
+                .overrides_with("validate"),
+        )
+        .arg(
+            Arg::with_name("enable")
+                .help("Enables a Wasm proposal when validating, such as threads or multi-memory")
+                .long("enable")
+                .value_name("PROPOSAL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("disable")
+                .help("Disables a Wasm proposal when validating")
+                .long("disable")
+                .value_name("PROPOSAL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("digests")
+                .help("Records the SHA-256 digest of each bundled file")
+                .long("digests"),
+        )
+        .arg(
+            Arg::with_name("encrypt")
+                .help("Encrypts the bundled files with the 256-bit key in the file")
+                .long("encrypt")
+                .value_name("KEY")
+                .takes_value(true)
+                .conflicts_with("digests"),
+        )
+        .arg(
+            Arg::with_name("cipher")
+                .help("Sets the cipher the bundled files are encrypted with")
+                .long("cipher")
+                .takes_value(true)
+                .possible_values(&["aes-256-gcm", "chacha20-poly1305"])
+                .default_value("aes-256-gcm"),
+        )
+        .arg(
+            Arg::with_name("sign")
+                .help("Signs the resources section with the Ed25519 private key in the file")
+                .long("sign")
+                .value_name("KEY")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sign-module")
+                .help("Makes the signature cover the rest of the module too")
+                .long("sign-module")
+                .requires("sign"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .help("Sets the compression of the bundled files")
+                .long("compress")
+                .takes_value(true)
+                .possible_values(&["none", "gzip", "zstd", "lz4"])
+                .default_value("none"),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract bundled resource files from a Wasm file")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input Wasm file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("Sets the directory to extract files into")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(section_arg())
+                .arg(module_arg())
+                .arg(key_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List bundled resource files in a Wasm file")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input Wasm file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(section_arg())
+                .arg(module_arg())
+                .arg(key_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verify bundled resource files against their signature, digests or sources")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input Wasm file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("pubkey")
+                        .help("Checks the signature against the Ed25519 public key in the file")
+                        .long("pubkey")
+                        .value_name("KEY")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("against")
+                        .help("Reports files missing from, added to or changed in the directory")
+                        .long("against")
+                        .value_name("DIR")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .help("Reports files missing from, added to or changed in the manifest")
+                        .short("-m")
+                        .long("manifest")
+                        .takes_value(true),
+                )
+                .arg(include_arg())
+                .arg(exclude_arg())
+                .arg(ignore_files_arg())
+                .arg(prefix_arg())
+                .arg(section_arg())
+                .arg(module_arg())
+                .arg(key_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("List bundled resource files added, removed or modified between Wasm files")
+                .after_help("Exits with status 1 if any files differ, as diff(1) does.")
+                .arg(
+                    Arg::with_name("OLD")
+                        .help("Sets the old Wasm file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("NEW")
+                        .help("Sets the new Wasm file")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("unified")
+                        .help("Shows unified diffs of modified text files up to 64 KiB")
+                        .short("-u")
+                        .long("unified"),
+                )
+                .arg(section_arg())
+                .arg(module_arg())
+                .arg(key_arg()),
+        )
+        .usage(
+            "find dir -type f | wasm-bundle INPUT OUTPUT
+    wasm-bundle INPUT OUTPUT PATH...
+    wasm-bundle --manifest wasm-bundle.toml INPUT OUTPUT
+    wasm-bundle extract INPUT DIR
+    wasm-bundle list INPUT
+    wasm-bundle verify [--pubkey KEY] [--against DIR] [--manifest FILE] INPUT
+    wasm-bundle diff [--unified] OLD NEW",
         )
-        .usage("find dir -type f | wasm-bundle INPUT OUTPUT")
         .get_matches();
 
-    let input_path = matches.value_of("INPUT").unwrap();
-    let output_path = matches.value_of("OUTPUT").unwrap();
+    // Like diff(1), `diff` exits with 1 when it finds differences.
+    let mut differ = false;
+    let result = match matches.subcommand() {
+        ("extract", Some(matches)) => {
+            let input_path = matches.value_of("INPUT").unwrap();
+            let dir = matches.value_of("DIR").unwrap();
 
-    // Create tar archive from the file list read
-    let mut reader = std::io::stdin();
-    let paths = read_paths(&mut reader).expect("couldn't read file list");
-    let mut archive = tempfile::tempfile().expect("couldn't create a temp file");
+            std::fs::read(input_path)
+                .map_err(with_path(input_path))
+                .and_then(|input| {
+                    let section = matches.value_of("section").unwrap();
+                    let (_, module) = find_module(&input, &target(matches)?)?;
+                    let key = encryption_key(matches)?;
+                    extract_with_key(section, module, Path::new(dir), key.as_ref())
+                })
+        }
+        ("list", Some(matches)) => {
+            let input_path = matches.value_of("INPUT").unwrap();
 
-    let prefix = matches.value_of("prefix").unwrap();
-    create_archive(paths, &prefix, &mut archive).expect("couldn't create archive");
+            std::fs::read(input_path)
+                .map_err(with_path(input_path))
+                .and_then(|input| {
+                    let section = matches.value_of("section").unwrap();
+                    list(
+                        section,
+                        &target(matches)?,
+                        encryption_key(matches)?.as_ref(),
+                        &input,
+                        &mut Output::default(),
+                    )
+                })
+        }
+        ("verify", Some(matches)) => {
+            let input_path = matches.value_of("INPUT").unwrap();
+
+            std::fs::read(input_path)
+                .map_err(with_path(input_path))
+                .and_then(|input| {
+                    let manifest = match matches.value_of("manifest") {
+                        Some(path) => Some(Manifest::from_path(path)?),
+                        None => None,
+                    };
+                    let sources = against(matches, manifest.as_ref())?;
 
-    // Filter out the existing .resources section
-    let input = std::fs::read(&input_path).expect("couldn't open input file");
-    let mut output = std::fs::File::create(&output_path).expect("couldn't create output file");
+                    // Options given explicitly take precedence over the
+                    // manifest
+                    let section = match manifest.as_ref().and_then(Manifest::section) {
+                        Some(section) if matches.occurrences_of("section") == 0 => section,
+                        _ => matches.value_of("section").unwrap(),
+                    };
+                    verify_module(
+                        (input_path, &input),
+                        section,
+                        &target(matches)?,
+                        matches.value_of("pubkey"),
+                        encryption_key(matches)?.as_ref(),
+                        sources.as_ref().map(|(path, builder)| (*path, builder)),
+                        &mut Output::default(),
+                    )
+                })
+        }
+        ("diff", Some(matches)) => {
+            let old_path = matches.value_of("OLD").unwrap();
+            let new_path = matches.value_of("NEW").unwrap();
 
-    let section = matches.value_of("section").unwrap();
-    filter(&section, input.as_slice(), &mut output).expect("couldn't filter sections");
+            std::fs::read(old_path)
+                .map_err(with_path(old_path))
+                .and_then(|old| Ok((old, std::fs::read(new_path).map_err(with_path(new_path))?)))
+                .and_then(|(old, new)| {
+                    diff(
+                        matches.value_of("section").unwrap(),
+                        &target(matches)?,
+                        encryption_key(matches)?.as_ref(),
+                        matches.is_present("unified"),
+                        (&old, &new),
+                        &mut Output::default(),
+                    )
+                })
+                .map(|changed| differ = changed)
+        }
+        _ => bundle(&matches),
+    };
 
-    // Append a custom .resources section with the created archive
-    append(&section, &archive, &mut output).expect("couldn't append custom section");
+    if let Err(error) = result {
+        eprintln!("wasm-bundle: {}", error);
+        std::process::exit(error.exit_code());
+    }
+    if differ {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_paths() {
+        let mut input: &[u8] = b"a\nb\0c \0last";
+        let paths: Vec<PathBuf> = vec!["a\nb".into(), "c ".into(), "last".into()];
+        assert_eq!(read_null_paths(&mut input).unwrap(), paths);
+
+        let mut input: &[u8] = b"a\0b\0";
+        let paths: Vec<PathBuf> = vec!["a".into(), "b".into()];
+        assert_eq!(read_null_paths(&mut input).unwrap(), paths);
+
+        let mut input: &[u8] = b"";
+        assert!(read_null_paths(&mut input).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mapping_splits() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        std::fs::write(format!("{}/a=b", dir), "").unwrap();
+
+        // An existing file is never split, even with `=` in its name.
+        let arg = format!("{}/a=b", dir);
+        assert_eq!(mapping(&arg), (arg.clone().into(), None));
+
+        // Otherwise the split is at the `=` after an existing source...
+        let arg = format!("{}/a=b=c=d", dir);
+        let source = format!("{}/a=b", dir);
+        assert_eq!(mapping(&arg), (source.into(), Some("c=d".into())));
+
+        // ...falling back to the first `=`.
+        let arg = format!("{}/x=y=z", dir);
+        let source = format!("{}/x", dir);
+        assert_eq!(mapping(&arg), (source.into(), Some("y=z".into())));
+
+        let arg = format!("{}/x", dir);
+        assert_eq!(mapping(&arg), (arg.clone().into(), None));
+    }
 }
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::{BundleError, PathContext, Result};
+use crate::glob::Globs;
+use crate::BundleBuilder;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSpec {
+    source: PathBuf,
+    destination: Option<PathBuf>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    ignore_files: bool,
+}
+
+/// A declarative description of what to bundle, typically read from a
+/// `wasm-bundle.toml` file:
+///
+/// ```toml
+/// section = ".enarx.resources"
+/// compression = "zstd"
+///
+/// [[file]]
+/// source = "config/prod.toml"
+/// destination = "/etc/app.toml"
+///
+/// [[file]]
+/// source = "assets"
+/// exclude = ["*.map", ".git"]
+/// ```
+///
+/// Sources are relative to the directory containing the manifest, and
+/// directories are walked recursively, keeping the files matching
+/// `include` (all by default) and not matching `exclude`, nor, with
+/// `ignore_files = true`, any `.gitignore` or `.bundleignore`.  Files are
+/// stored at `destination` in the bundle, which defaults to the
+/// source path.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    section: Option<String>,
+    compression: Option<String>,
+    #[serde(default, rename = "file")]
+    files: Vec<FileSpec>,
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).with_path(path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_str(&contents, base).map_err(|e| match e {
+            BundleError::InvalidManifest { message, .. } => BundleError::InvalidManifest {
+                path: Some(path.to_path_buf()),
+                message,
+            },
+            e => e,
+        })
+    }
+
+    /// Parses a manifest, resolving sources relative to `base`.
+    pub fn from_str(contents: &str, base: impl AsRef<Path>) -> Result<Self> {
+        let mut manifest: Self =
+            toml::from_str(contents).map_err(|e| BundleError::InvalidManifest {
+                path: None,
+                message: e.to_string(),
+            })?;
+        manifest.base = base.as_ref().to_path_buf();
+        Ok(manifest)
+    }
+
+    /// Returns the name of the resources section set by the manifest,
+    /// if any.
+    pub fn section(&self) -> Option<&str> {
+        self.section.as_deref()
+    }
+
+    /// Adds the files described by the manifest to `builder`, and sets
+    /// its section name and compression if given.
+    pub fn apply(&self, builder: &mut BundleBuilder) -> Result<()> {
+        if let Some(section) = &self.section {
+            builder.section(section);
+        }
+
+        if let Some(compression) = &self.compression {
+            builder.compression(compression.parse()?);
+        }
+
+        for file in &self.files {
+            let path = self.base.join(&file.source);
+            let name = file.destination.as_ref().unwrap_or(&file.source);
+
+            if path.is_dir() {
+                let mut globs = Globs::new(&file.include, &file.exclude)?;
+                if file.ignore_files {
+                    globs = globs.with_ignore_files();
+                }
+                builder.add_directory_with_globs(path, name, globs);
+            } else {
+                builder.add_file(path, name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid(contents: &str) -> bool {
+        matches!(
+            Manifest::from_str(contents, ""),
+            Err(BundleError::InvalidManifest { .. })
+        )
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(!is_invalid("section = \"x\"\n[[file]]\nsource = \"a\""));
+        assert!(is_invalid("sections = \"x\""));
+        assert!(is_invalid("[[file]]\nsource = \"a\"\ndest = \"b\""));
+        assert!(is_invalid("[[files]]\nsource = \"a\""));
+    }
+
+    #[test]
+    fn destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("config.toml"), "").unwrap();
+        std::fs::create_dir(dir.path().join("assets")).unwrap();
+        std::fs::write(dir.path().join("assets/app.js"), "").unwrap();
+        std::fs::write(dir.path().join("assets/app.js.map"), "").unwrap();
+
+        let manifest = Manifest::from_str(
+            r#"
+            [[file]]
+            source = "config.toml"
+            destination = "etc/app.toml"
+
+            [[file]]
+            source = "assets"
+            exclude = ["*.map"]
+            "#,
+            dir.path(),
+        )
+        .unwrap();
+        let mut builder = BundleBuilder::new();
+        manifest.apply(&mut builder).unwrap();
+
+        let digests = builder.file_digests().unwrap();
+        let names: Vec<_> = digests.iter().map(|(name, _)| name.into_owned()).collect();
+        assert_eq!(
+            names,
+            [Path::new("assets/app.js"), Path::new("etc/app.toml")]
+        );
+    }
+}
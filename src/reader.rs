@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::archive::resolve;
+use crate::codec::open_archive;
+use crate::crypt::EncryptionKey;
+use crate::digest::{digest_section, Digests};
+use crate::error::{BundleError, Result};
+use crate::section::find_section;
+use crate::RESOURCES_SECTION;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+/// A file bundled in a Wasm module.
+#[derive(Clone, Copy)]
+pub struct Resource<'a> {
+    path: &'a Path,
+    header: &'a tar::Header,
+    link: Option<&'a Path>,
+    offset: Option<usize>,
+    data: &'a [u8],
+    digest: Option<&'a [u8; 32]>,
+}
+
+impl<'a> Resource<'a> {
+    /// Returns the path of the file within the bundle.
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+
+    /// Returns the tar header of the file, carrying its type, mode and
+    /// modification time.
+    pub fn header(&self) -> &'a tar::Header {
+        self.header
+    }
+
+    /// Returns the target of the entry if it is a symbolic or hard
+    /// link.
+    pub fn link_name(&self) -> Option<&'a Path> {
+        self.link
+    }
+
+    /// Returns the offset of the file contents within the Wasm module,
+    /// unless the archive is compressed.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Returns the contents of the file.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the SHA-256 digest recorded for the file, if the bundle
+    /// has digests and this is a regular file.
+    pub fn digest(&self) -> Option<&'a [u8; 32]> {
+        self.digest
+    }
+
+    /// Checks the contents of the file against the digest recorded for
+    /// it, returning `None` if there is none.
+    pub fn verify(&self) -> Option<bool> {
+        let digest: [u8; 32] = Sha256::digest(self.data).into();
+        self.digest.map(|recorded| *recorded == digest)
+    }
+
+    /// Returns a reader over the contents of the file.
+    pub fn reader(&self) -> Cursor<&'a [u8]> {
+        Cursor::new(self.data)
+    }
+}
+
+struct Index {
+    path: PathBuf,
+    header: tar::Header,
+    link: Option<PathBuf>,
+    range: Range<usize>,
+    digest: Option<[u8; 32]>,
+}
+
+/// Reads the files bundled in a Wasm module, without instantiating
+/// it.
+///
+/// Unless the archive is compressed, the file contents are borrowed
+/// from the module bytes.
+pub struct ResourceReader<'a> {
+    archive: Cow<'a, [u8]>,
+    offset: Option<usize>,
+    index: Vec<Index>,
+    /// The position in `index` of the last entry for each normalized path.
+    paths: HashMap<PathBuf, usize>,
+    digests: Option<Digests>,
+}
+
+impl<'a> ResourceReader<'a> {
+    /// Reads the files bundled in the default `.enarx.resources`
+    /// section of `module`.
+    pub fn from_wasm(module: &'a [u8]) -> Result<Self> {
+        Self::from_wasm_section(module, RESOURCES_SECTION)
+    }
+
+    /// Reads the files bundled in the custom section named `section`
+    /// of `module`.
+    pub fn from_wasm_section(module: &'a [u8], section: &str) -> Result<Self> {
+        Self::from_wasm_section_with_key(module, section, None)
+    }
+
+    /// Like [`ResourceReader::from_wasm_section`], but decrypting the
+    /// files with `key` if they are encrypted.
+    pub fn from_wasm_section_with_key(
+        module: &'a [u8],
+        section: &str,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Self> {
+        let (offset, data) = find_section(section, module)?
+            .ok_or_else(|| BundleError::SectionNotFound(section.to_string()))?;
+        let invalid_archive = |e| BundleError::invalid_archive(section, e);
+        let archive = open_archive(section, data, key)?;
+        // An uncompressed archive is stored at the end of the section.
+        let offset = match archive {
+            Cow::Borrowed(archive) => Some(offset + data.len() - archive.len()),
+            Cow::Owned(_) => None,
+        };
+        let digests = match find_section(&digest_section(section), module)? {
+            Some((_, data)) => Some(
+                Digests::parse(data)
+                    .map_err(|e| BundleError::invalid_archive(&digest_section(section), e))?,
+            ),
+            None => None,
+        };
+        let mut index = Vec::new();
+        let mut paths = HashMap::new();
+
+        for entry in tar::Archive::new(archive.as_ref())
+            .entries()
+            .map_err(invalid_archive)?
+        {
+            let entry = entry.map_err(invalid_archive)?;
+            let start = entry.raw_file_position() as usize;
+            let end = start + entry.size() as usize;
+            if end > archive.len() {
+                return Err(invalid_archive(ErrorKind::UnexpectedEof.into()));
+            }
+
+            let path = entry.path().map_err(invalid_archive)?.into_owned();
+            let digest = match &digests {
+                Some(digests) if entry.header().entry_type().is_file() => {
+                    digests.get(&path).copied()
+                }
+                _ => None,
+            };
+            paths.insert(normalize(&path), index.len());
+            index.push(Index {
+                path,
+                header: entry.header().clone(),
+                link: entry
+                    .link_name()
+                    .map_err(invalid_archive)?
+                    .map(|link| link.into_owned()),
+                range: start..end,
+                digest,
+            });
+        }
+
+        Ok(Self {
+            archive,
+            offset,
+            index,
+            paths,
+            digests,
+        })
+    }
+
+    fn resource<'r>(&'r self, index: &'r Index) -> Resource<'r> {
+        Resource {
+            path: &index.path,
+            header: &index.header,
+            link: index.link.as_deref(),
+            offset: self.offset.map(|offset| offset + index.range.start),
+            data: &self.archive[index.range.clone()],
+            digest: index.digest.as_ref(),
+        }
+    }
+
+    fn lookup(&self, path: &Path) -> Option<&Index> {
+        self.paths.get(path).map(|&i| &self.index[i])
+    }
+
+    /// Returns the file at `path`, if any.
+    ///
+    /// Leading `/` and `.` components are ignored, and later entries
+    /// replace earlier ones with the same path, as when extracting.
+    /// Symbolic links along the path and hard links are followed, as
+    /// are `..` components, as long as they stay inside the bundle.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Resource<'_>> {
+        let path = resolve(path.as_ref(), |path| match self.lookup(path) {
+            Some(index) if index.header.entry_type() == tar::EntryType::Symlink => {
+                index.link.clone()
+            }
+            _ => None,
+        })?;
+
+        let mut index = self.lookup(&path)?;
+        if index.header.entry_type() == tar::EntryType::Link {
+            index = self.lookup(&normalize(index.link.as_deref()?))?;
+        }
+        Some(self.resource(index))
+    }
+
+    /// Returns the digests recorded for the bundled files, if any.
+    pub fn digests(&self) -> Option<&Digests> {
+        self.digests.as_ref()
+    }
+
+    /// Iterates over the bundled files, in archive order.
+    pub fn iter(&self) -> impl Iterator<Item = Resource<'_>> {
+        self.index.iter().map(move |index| self.resource(index))
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::bundle_module;
+
+    #[test]
+    fn get_last_entry() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in &[("./a", "old"), ("b/c", "c"), ("a", "new")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "d", "b").unwrap();
+        let module = bundle_module(&builder.into_inner().unwrap());
+
+        let reader = ResourceReader::from_wasm(&module).unwrap();
+        assert_eq!(reader.get("/a").unwrap().data(), b"new");
+        assert_eq!(reader.get("d/c").unwrap().data(), b"c");
+        assert!(reader.get("b/a").is_none());
+        assert_eq!(reader.iter().count(), 4);
+    }
+}
@@ -0,0 +1,508 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::digest::{digest_section, DIGEST_SUFFIX};
+use crate::error::{BundleError, Result};
+use crate::signature::{sign, signature_section, Scope, SIGNATURE_SUFFIX};
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{copy, sink, BufReader, Cursor, ErrorKind, Read, Write};
+use std::str::FromStr;
+use wasmparser::{Parser, Payload::*};
+
+/// The magic number and version at the start of a Wasm module.
+const PREAMBLE_SIZE: usize = 8;
+
+/// Reads an unsigned LEB128 number, keeping the bytes it was encoded
+/// with in `raw`.
+fn read_leb128(input: &mut impl Read, raw: &mut Vec<u8>) -> std::io::Result<u64> {
+    let mut result: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let mut byte = [0; 1];
+        input.read_exact(&mut byte)?;
+        raw.push(byte[0]);
+
+        if shift >= 64 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        result |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+const TYPE_SECTION: u8 = 1;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+
+/// Where to place the resources section in the module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// As the first section.
+    Start,
+    /// Right after the type section, or where it would be.
+    AfterType,
+    /// Right before the code section, or the data section if there is
+    /// no code.
+    BeforeCode,
+    /// As the last section.
+    End,
+}
+
+impl FromStr for Position {
+    type Err = BundleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "start" => Ok(Position::Start),
+            "after-type" => Ok(Position::AfterType),
+            "before-code" => Ok(Position::BeforeCode),
+            "end" => Ok(Position::End),
+            _ => Err(BundleError::InvalidOption {
+                name: "position",
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Position::Start => "start",
+            Position::AfterType => "after-type",
+            Position::BeforeCode => "before-code",
+            Position::End => "end",
+        })
+    }
+}
+
+/// Turns an error reading the section starting at `offset` into a
+/// parse error, unless it comes from the underlying reader.
+fn parse_error(offset: u64, error: std::io::Error) -> BundleError {
+    let message = match error.kind() {
+        ErrorKind::UnexpectedEof => "unexpected end of module",
+        ErrorKind::InvalidData => "invalid LEB128 number",
+        _ => return error.into(),
+    };
+
+    BundleError::Parse {
+        offset,
+        message: message.to_string(),
+    }
+}
+
+/// Returns whether the custom section `name` is the resources section
+/// `section` or one accompanying it.
+fn is_bundle_section(section: &str, name: &[u8]) -> bool {
+    name.strip_prefix(section.as_bytes()).is_some_and(|suffix| {
+        suffix.is_empty()
+            || suffix == DIGEST_SUFFIX.as_bytes()
+            || suffix == SIGNATURE_SUFFIX.as_bytes()
+    })
+}
+
+/// Passes writes through to `inner`, hashing them on the way if there
+/// is a hasher.
+struct Hashing<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies a Wasm module from `input` to `output`, dropping any
+/// top-level custom section named `section`, along with the sections
+/// accompanying it.
+///
+/// Sections are streamed one at a time rather than buffered, so memory
+/// use doesn't depend on the size of the module.
+pub fn filter(section: &str, input: impl Read, output: &mut impl Write) -> Result<()> {
+    filter_with(section, input, output, |_, _| Ok(()))
+}
+
+/// Like [`filter`], but with the resources section holding the contents
+/// of `archive` placed at `position`, followed by `digests` if given,
+/// and signed with `key` if given.
+///
+/// The signature section comes last, so that it can cover the rest of
+/// the module, which is hashed as it is copied.
+pub(crate) fn splice<W: Write>(
+    section: &str,
+    position: Position,
+    archive: &std::fs::File,
+    digests: Option<&[u8]>,
+    key: Option<(&SigningKey, Scope)>,
+    mut input: impl Read,
+    output: &mut W,
+) -> Result<()> {
+    let mut preamble = [0; PREAMBLE_SIZE];
+    input
+        .read_exact(&mut preamble)
+        .map_err(|e| parse_error(0, e))?;
+
+    // Components have sections of their own, so only the start or the
+    // end is meaningful there.
+    let component = preamble[6..] == [1, 0];
+    if component && position != Position::Start && position != Position::End {
+        return Err(BundleError::InvalidOption {
+            name: "position for a component",
+            value: position.to_string(),
+        });
+    }
+
+    let payload = match key {
+        Some(_) => {
+            let mut hasher = Sha256::new();
+            let mut archive = archive;
+            archive.seek(std::io::SeekFrom::Start(0))?;
+            copy(&mut archive, &mut hasher)?;
+            Some(hasher.finalize().into())
+        }
+        None => None,
+    };
+    let mut output = Hashing {
+        inner: output,
+        hasher: match key {
+            Some((_, Scope::Module)) => Some(Sha256::new()),
+            _ => None,
+        },
+    };
+
+    let input = Cursor::new(preamble).chain(input);
+    let mut inserted = false;
+    let mut after_type = false;
+
+    filter_with(section, input, &mut output, |next, output| {
+        let here = match position {
+            Position::Start => true,
+            Position::AfterType => {
+                after_type || next.is_none_or(|id| id != 0 && id != TYPE_SECTION)
+            }
+            Position::BeforeCode => next.is_none_or(|id| id == CODE_SECTION || id == DATA_SECTION),
+            Position::End => next.is_none(),
+        };
+        after_type = next == Some(TYPE_SECTION);
+
+        if here && !inserted {
+            append(section, archive, &mut output.inner)?;
+            if let Some(digests) = digests {
+                write_section(&digest_section(section), digests, &mut output.inner)?;
+            }
+            inserted = true;
+        }
+
+        if let (None, Some((key, _)), Some(payload)) = (next, key, &payload) {
+            let module: Option<[u8; 32]> = output.hasher.take().map(|h| h.finalize().into());
+            let signature = sign(key, section, payload, module.as_ref());
+            write_section(&signature_section(section), &signature, &mut output.inner)?;
+        }
+        Ok(())
+    })
+}
+
+/// Walks the sections of the module, calling `hook` with the ID of
+/// each section before it is copied, and with `None` at the end.
+fn filter_with<W: Write>(
+    section: &str,
+    input: impl Read,
+    output: &mut W,
+    mut hook: impl FnMut(Option<u8>, &mut W) -> Result<()>,
+) -> Result<()> {
+    let mut input = BufReader::new(input);
+
+    let mut preamble = [0; PREAMBLE_SIZE];
+    input
+        .read_exact(&mut preamble)
+        .map_err(|e| parse_error(0, e))?;
+    if &preamble[..4] != b"\0asm" {
+        return Err(BundleError::Parse {
+            offset: 0,
+            message: "not a WebAssembly module".to_string(),
+        });
+    }
+    output.write_all(&preamble)?;
+
+    let mut offset = PREAMBLE_SIZE as u64;
+    loop {
+        let mut id = [0; 1];
+        if input.read(&mut id)? == 0 {
+            hook(None, output)?;
+            break;
+        }
+        hook(Some(id[0]), output)?;
+
+        let mut header = id.to_vec();
+        let size = read_leb128(&mut input, &mut header).map_err(|e| parse_error(offset, e))?;
+        let start = offset;
+        offset += header.len() as u64 + size;
+        let mut contents = (&mut input).take(size);
+
+        // Custom sections start with their name, which is all we need
+        // to look at to decide whether to keep them.
+        if id[0] == 0 {
+            let mut raw = Vec::new();
+            let len = read_leb128(&mut contents, &mut raw).map_err(|e| parse_error(start, e))?;
+            let mut name = Vec::new();
+            (&mut contents).take(len).read_to_end(&mut name)?;
+            if name.len() as u64 != len {
+                return Err(parse_error(start, ErrorKind::UnexpectedEof.into()));
+            }
+
+            if is_bundle_section(section, &name) {
+                let n = copy(&mut contents, &mut sink())?;
+                if n + (raw.len() + name.len()) as u64 != size {
+                    return Err(parse_error(start, ErrorKind::UnexpectedEof.into()));
+                }
+                continue;
+            }
+
+            header.extend_from_slice(&raw);
+            header.extend_from_slice(&name);
+        }
+
+        output.write_all(&header)?;
+        copy(&mut contents, output)?;
+        if contents.limit() != 0 {
+            return Err(parse_error(start, ErrorKind::UnexpectedEof.into()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a custom section named `section` holding the contents of
+/// `archive` to `writer`.
+pub fn append(
+    section: &str,
+    mut archive: &std::fs::File,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let mut header: Vec<u8> = Vec::new();
+    let name = section.as_bytes();
+    leb128::write::unsigned(&mut header, name.len() as u64)?;
+    header.write_all(name)?;
+    let size = archive.seek(std::io::SeekFrom::End(0))?;
+
+    writer.write_all(&[0])?;
+    leb128::write::unsigned(writer, size + header.len() as u64)?;
+    writer.write_all(&header)?;
+
+    let _ = archive.seek(std::io::SeekFrom::Start(0))?;
+    loop {
+        let mut buf = [0; 4096];
+        let n = archive.read(&mut buf[..])?;
+
+        if n == 0 {
+            break;
+        }
+
+        writer.write_all(&buf[..n])?;
+    }
+
+    Ok(())
+}
+
+/// Writes a custom section named `name` holding `data` to `writer`.
+pub(crate) fn write_section(
+    name: &str,
+    data: &[u8],
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let mut header: Vec<u8> = Vec::new();
+    leb128::write::unsigned(&mut header, name.len() as u64)?;
+    header.write_all(name.as_bytes())?;
+
+    writer.write_all(&[0])?;
+    leb128::write::unsigned(writer, (header.len() + data.len()) as u64)?;
+    writer.write_all(&header)?;
+    writer.write_all(data)
+}
+
+/// The header of a Wasm module with no sections.
+#[cfg(test)]
+pub(crate) const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+/// Returns a module with no other section than the default resources
+/// section, holding the tar archive `archive`.
+#[cfg(test)]
+pub(crate) fn bundle_module(archive: &[u8]) -> Vec<u8> {
+    let mut module = EMPTY_MODULE.to_vec();
+    write_section(crate::RESOURCES_SECTION, archive, &mut module).unwrap();
+    module
+}
+
+/// Looks up the top-level custom section named `section` in `input`,
+/// returning the offset of its contents along with the contents.
+pub fn find_section<'a>(section: &str, input: &'a [u8]) -> Result<Option<(usize, &'a [u8])>> {
+    let mut depth = 0;
+
+    for payload in Parser::new(0).parse_all(input) {
+        match payload? {
+            CustomSection(reader) if depth == 0 && reader.name() == section => {
+                return Ok(Some((reader.data_offset() as usize, reader.data())))
+            }
+            // Only look at the top-level module; nested modules carry
+            // their own custom sections.
+            ModuleSection { .. } | ComponentSection { .. } => depth += 1,
+            End(_) => depth -= 1,
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// Like [`find_section`], but failing if there is more than one such
+/// section, since checking only the first would let the others through.
+pub(crate) fn find_unique_section<'a>(
+    section: &str,
+    input: &'a [u8],
+) -> Result<Option<(usize, &'a [u8])>> {
+    let mut found = None;
+    let mut depth = 0;
+
+    for payload in Parser::new(0).parse_all(input) {
+        match payload? {
+            CustomSection(reader) if depth == 0 && reader.name() == section => {
+                if found.is_some() {
+                    return Err(BundleError::DuplicateSection(section.to_string()));
+                }
+                found = Some((reader.data_offset() as usize, reader.data()));
+            }
+            ModuleSection { .. } | ComponentSection { .. } => depth += 1,
+            End(_) => depth -= 1,
+            _ => {}
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BundleBuilder, RESOURCES_SECTION as R};
+
+    /// Returns the sections of `module` in order, custom sections by
+    /// name and the others by ID.
+    fn sections(module: &[u8]) -> Vec<String> {
+        let mut input = &module[PREAMBLE_SIZE..];
+        let mut sections = Vec::new();
+        while !input.is_empty() {
+            let id = input[0];
+            input = &input[1..];
+            let size = read_leb128(&mut input, &mut Vec::new()).unwrap() as usize;
+            let (mut contents, rest) = input.split_at(size);
+            input = rest;
+            sections.push(match id {
+                0 => {
+                    let len = read_leb128(&mut contents, &mut Vec::new()).unwrap() as usize;
+                    String::from_utf8(contents[..len].to_vec()).unwrap()
+                }
+                id => id.to_string(),
+            });
+        }
+        sections
+    }
+
+    /// Bundles a file into `module` at `position`, returning the
+    /// sections of the output.
+    fn place(module: &[u8], position: Position) -> Vec<String> {
+        let mut output = Vec::new();
+        BundleBuilder::new()
+            .position(position)
+            .add_bytes("a", "a")
+            .write(module, &mut output)
+            .unwrap();
+        wasmparser::Validator::new().validate_all(&output).unwrap();
+        sections(&output)
+    }
+
+    /// Returns the module given in `wat`, with a custom section named
+    /// "before" ahead of all of its sections.
+    fn custom_first(wat: &str) -> Vec<u8> {
+        let module = wat::parse_str(wat).unwrap();
+        let mut output = module[..PREAMBLE_SIZE].to_vec();
+        write_section("before", b"", &mut output).unwrap();
+        output.extend_from_slice(&module[PREAMBLE_SIZE..]);
+        output
+    }
+
+    #[test]
+    fn positions() {
+        // data.drop needs a data count section, which comes before code.
+        let module = custom_first(
+            r#"(module
+                (type (func))
+                (func (type 0) data.drop 0)
+                (memory 1)
+                (data (i32.const 0) "x"))"#,
+        );
+        assert_eq!(
+            sections(&module),
+            ["before", "1", "3", "5", "12", "10", "11"]
+        );
+
+        assert_eq!(
+            place(&module, Position::Start),
+            [R, "before", "1", "3", "5", "12", "10", "11"]
+        );
+        assert_eq!(
+            place(&module, Position::AfterType),
+            ["before", "1", R, "3", "5", "12", "10", "11"]
+        );
+        assert_eq!(
+            place(&module, Position::BeforeCode),
+            ["before", "1", "3", "5", "12", R, "10", "11"]
+        );
+        assert_eq!(
+            place(&module, Position::End),
+            ["before", "1", "3", "5", "12", "10", "11", R]
+        );
+    }
+
+    #[test]
+    fn positions_without_type_or_code() {
+        let module = custom_first(r#"(module (memory 1) (data (i32.const 0) "x"))"#);
+        assert_eq!(sections(&module), ["before", "5", "11"]);
+        assert_eq!(
+            place(&module, Position::AfterType),
+            ["before", R, "5", "11"]
+        );
+        assert_eq!(
+            place(&module, Position::BeforeCode),
+            ["before", "5", R, "11"]
+        );
+
+        let module = custom_first("(module (memory 1))");
+        assert_eq!(place(&module, Position::AfterType), ["before", R, "5"]);
+        assert_eq!(place(&module, Position::BeforeCode), ["before", "5", R]);
+
+        for position in &[
+            Position::Start,
+            Position::AfterType,
+            Position::BeforeCode,
+            Position::End,
+        ] {
+            assert_eq!(place(EMPTY_MODULE, *position), [R]);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::digest::digest_section;
+use crate::error::{BundleError, PathContext, Result};
+use crate::section::{filter, find_unique_section};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::path::Path;
+
+/// What a signature covers besides the resources section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Only the contents of the resources section.
+    Resources,
+    /// The rest of the module too, that is all of its sections other
+    /// than the resources section and those accompanying it.
+    Module,
+}
+
+/// Appended to the name of the resources section to name the section
+/// holding its signature.
+pub(crate) const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// The version of the signature section, which holds this, the scope
+/// and the signature itself.
+const VERSION: u8 = 1;
+const SIGNATURE_SIZE: usize = 2 + Signature::BYTE_SIZE;
+
+/// Keeps signatures from being mistaken for those of anything else
+/// signed with the same key.
+const CONTEXT: &[u8] = b"wasm-bundle signature\0";
+
+/// Returns the name of the section holding the signature of the
+/// resources section `section`.
+pub fn signature_section(section: &str) -> String {
+    format!("{}{}", section, SIGNATURE_SUFFIX)
+}
+
+fn invalid_key(path: &Path, message: impl ToString) -> BundleError {
+    BundleError::InvalidKey {
+        path: path.to_path_buf(),
+        message: message.to_string(),
+    }
+}
+
+/// Reads the file at `path` as either PEM text or raw bytes.
+fn read_key(path: &Path) -> Result<std::result::Result<String, Vec<u8>>> {
+    let bytes = std::fs::read(path).with_path(path)?;
+    Ok(match String::from_utf8(bytes) {
+        Ok(text) if text.trim_start().starts_with("-----BEGIN") => Ok(text),
+        Ok(text) => Err(text.into_bytes()),
+        Err(e) => Err(e.into_bytes()),
+    })
+}
+
+/// Reads an Ed25519 private key from the file at `path`, either in
+/// PKCS#8 format, as written by `openssl genpkey -algorithm ed25519`,
+/// or as the 32 raw bytes of the secret key.
+pub fn read_signing_key(path: impl AsRef<Path>) -> Result<SigningKey> {
+    let path = path.as_ref();
+
+    match read_key(path)? {
+        Ok(pem) => SigningKey::from_pkcs8_pem(&pem).map_err(|e| invalid_key(path, e)),
+        Err(bytes) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(secret) => Ok(SigningKey::from_bytes(&secret)),
+            Err(_) => SigningKey::from_pkcs8_der(&bytes).map_err(|e| invalid_key(path, e)),
+        },
+    }
+}
+
+/// Reads an Ed25519 public key from the file at `path`, either in
+/// SubjectPublicKeyInfo format, as written by `openssl pkey -pubout`,
+/// or as the 32 raw bytes of the key.
+pub fn read_verifying_key(path: impl AsRef<Path>) -> Result<VerifyingKey> {
+    let path = path.as_ref();
+
+    match read_key(path)? {
+        Ok(pem) => VerifyingKey::from_public_key_pem(&pem).map_err(|e| invalid_key(path, e)),
+        Err(bytes) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+            Ok(key) => VerifyingKey::from_bytes(&key).map_err(|e| invalid_key(path, e)),
+            Err(_) => VerifyingKey::from_public_key_der(&bytes).map_err(|e| invalid_key(path, e)),
+        },
+    }
+}
+
+/// Returns what is signed: the name of the section along with the
+/// digests of its contents, and of the rest of the module if covered.
+fn message(section: &str, payload: &[u8; 32], module: Option<&[u8; 32]>) -> Vec<u8> {
+    let mut message = CONTEXT.to_vec();
+    message.push(module.is_some() as u8);
+    message.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    message.extend_from_slice(section.as_bytes());
+    message.extend_from_slice(payload);
+    if let Some(module) = module {
+        message.extend_from_slice(module);
+    }
+    message
+}
+
+/// Returns the contents of the signature section for the resources
+/// section `section`, given the digests of its contents and of the
+/// rest of the module if it is to be covered.
+pub(crate) fn sign(
+    key: &SigningKey,
+    section: &str,
+    payload: &[u8; 32],
+    module: Option<&[u8; 32]>,
+) -> Vec<u8> {
+    let signature = key.sign(&message(section, payload, module));
+    let mut contents = vec![VERSION, module.is_some() as u8];
+    contents.extend_from_slice(&signature.to_bytes());
+    contents
+}
+
+/// Checks the signature of the resources section `section` in the
+/// module `input` against `key`, returning what it covers.
+///
+/// Modules with more than one resources section, or more than one of
+/// the sections accompanying it, are rejected: the signature would only
+/// cover one of them.
+pub fn verify(section: &str, input: &[u8], key: &VerifyingKey) -> Result<Scope> {
+    let (_, payload) = find_unique_section(section, input)?
+        .ok_or_else(|| BundleError::SectionNotFound(section.into()))?;
+    let name = signature_section(section);
+    let (_, contents) = find_unique_section(&name, input)?
+        .ok_or_else(|| BundleError::SectionNotFound(name.clone()))?;
+    find_unique_section(&digest_section(section), input)?;
+
+    let invalid = || BundleError::InvalidSignature(section.to_string());
+    if contents.len() != SIGNATURE_SIZE || contents[0] != VERSION {
+        return Err(invalid());
+    }
+    let scope = match contents[1] {
+        0 => Scope::Resources,
+        1 => Scope::Module,
+        _ => return Err(invalid()),
+    };
+    let signature = Signature::from_slice(&contents[2..]).map_err(|_| invalid())?;
+
+    let payload = Sha256::digest(payload).into();
+    let module = match scope {
+        Scope::Resources => None,
+        Scope::Module => {
+            let mut hasher = Sha256::new();
+            filter(section, input, &mut hasher)?;
+            Some(hasher.finalize().into())
+        }
+    };
+
+    key.verify_strict(&message(section, &payload, module.as_ref()), &signature)
+        .map_err(|_| invalid())?;
+    Ok(scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::{write_section, EMPTY_MODULE};
+    use crate::{BundleBuilder, RESOURCES_SECTION};
+
+    fn signed_module(key: &SigningKey, scope: Scope) -> Vec<u8> {
+        let mut module = Vec::new();
+        BundleBuilder::new()
+            .add_bytes("a", "a")
+            .sign(key.clone(), scope)
+            .write(EMPTY_MODULE, &mut module)
+            .unwrap();
+        module
+    }
+
+    #[test]
+    fn verify_signed() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        for scope in &[Scope::Resources, Scope::Module] {
+            let module = signed_module(&key, *scope);
+            assert_eq!(
+                verify(RESOURCES_SECTION, &module, &key.verifying_key()).unwrap(),
+                *scope
+            );
+        }
+    }
+
+    #[test]
+    fn verify_wrong_key() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let module = signed_module(&key, Scope::Resources);
+        assert!(matches!(
+            verify(RESOURCES_SECTION, &module, &other.verifying_key()),
+            Err(BundleError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn verify_tampered() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verify = |module: &[u8]| verify(RESOURCES_SECTION, module, &key.verifying_key());
+
+        // Flip a byte of the bundled file.
+        for scope in &[Scope::Resources, Scope::Module] {
+            let mut module = signed_module(&key, *scope);
+            let (offset, data) = find_unique_section(RESOURCES_SECTION, &module)
+                .unwrap()
+                .unwrap();
+            let i = offset
+                + data
+                    .windows(2)
+                    .rposition(|window| window == b"a\0")
+                    .unwrap();
+            module[i] = b'b';
+            assert!(matches!(
+                verify(&module),
+                Err(BundleError::InvalidSignature(_))
+            ));
+        }
+
+        // Add a section, which only the module scope covers.
+        let mut module = signed_module(&key, Scope::Resources);
+        write_section("extra", b"", &mut module).unwrap();
+        assert_eq!(verify(&module).unwrap(), Scope::Resources);
+        let mut module = signed_module(&key, Scope::Module);
+        write_section("extra", b"", &mut module).unwrap();
+        assert!(matches!(
+            verify(&module),
+            Err(BundleError::InvalidSignature(_))
+        ));
+
+        // Claim a narrower scope.
+        let mut module = signed_module(&key, Scope::Module);
+        let (offset, _) = find_unique_section(&signature_section(RESOURCES_SECTION), &module)
+            .unwrap()
+            .unwrap();
+        module[offset + 1] = 0;
+        assert!(matches!(
+            verify(&module),
+            Err(BundleError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn verify_duplicate_section() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        for name in &[
+            RESOURCES_SECTION.to_string(),
+            signature_section(RESOURCES_SECTION),
+            digest_section(RESOURCES_SECTION),
+        ] {
+            let mut module = signed_module(&key, Scope::Module);
+            write_section(name, b"", &mut module).unwrap();
+            write_section(name, b"", &mut module).unwrap();
+            assert!(matches!(
+                verify(RESOURCES_SECTION, &module, &key.verifying_key()),
+                Err(BundleError::DuplicateSection(section)) if &section == name
+            ));
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::error::{BundleError, Result};
+use std::io::{self, ErrorKind, Read, Write};
+use wasmparser::{BinaryReaderError, Chunk, Parser, ValidPayload, WasmFeatures};
+
+/// Where the validator is within the top-level sections.
+enum State {
+    /// Passing the given number of bytes through to the parser.
+    Body(u64),
+    /// Dropping the given number of bytes of a custom section.
+    Skip(u64),
+    /// Collecting the ID and size of the next section, and the name
+    /// of custom sections.
+    Header,
+}
+
+/// Validates a Wasm module fed to it in arbitrary chunks.
+///
+/// The contents of top-level custom sections, which the validator
+/// doesn't look at, are dropped rather than buffered, so bundling a
+/// large resources section doesn't need to hold it in memory.
+struct Validator {
+    validator: wasmparser::Validator,
+    parser: Parser,
+    stack: Vec<Parser>,
+    buffer: Vec<u8>,
+    header: Vec<u8>,
+    state: State,
+    /// The number of bytes seen, and passed through to the parser.
+    offset: u64,
+    parsed: u64,
+    /// The offsets in the parsed and original module after each
+    /// custom section whose contents were dropped.
+    skipped: Vec<(u64, u64)>,
+}
+
+/// Reads an unsigned LEB128 number from the start of `bytes`, returning
+/// it along with its length, or `None` if it is incomplete.
+fn read_leb128(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut result: u64 = 0;
+
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        result |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((result, i + 1));
+        }
+    }
+
+    None
+}
+
+impl Validator {
+    fn new(features: WasmFeatures) -> Self {
+        let mut parser = Parser::new(0);
+        parser.set_features(features);
+
+        Self {
+            validator: wasmparser::Validator::new_with_features(features),
+            parser,
+            stack: Vec::new(),
+            buffer: Vec::new(),
+            header: Vec::new(),
+            // The magic number and version
+            state: State::Body(8),
+            offset: 0,
+            parsed: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    fn error(&self, error: BinaryReaderError) -> BundleError {
+        let offset = match self
+            .skipped
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= error.offset())
+        {
+            Some((at, original)) => error.offset() - at + original,
+            None => error.offset(),
+        };
+
+        BundleError::Parse {
+            offset,
+            message: error.message().to_string(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let n = match self.state {
+                State::Body(n) | State::Skip(n) => n.min(data.len() as u64) as usize,
+                State::Header => 1,
+            };
+            let (chunk, rest) = data.split_at(n);
+            data = rest;
+            self.offset += n as u64;
+
+            match self.state {
+                State::Body(remaining) => {
+                    self.forward(chunk)?;
+                    self.state = State::Body(remaining - n as u64);
+                }
+                State::Skip(remaining) => self.state = State::Skip(remaining - n as u64),
+                State::Header => {
+                    self.header.push(chunk[0]);
+                    self.check_header()?;
+                }
+            }
+
+            if let State::Body(0) | State::Skip(0) = self.state {
+                self.state = State::Header;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decides what to do with the section once enough of its header
+    /// has been collected.
+    fn check_header(&mut self) -> Result<()> {
+        let (size, len) = match read_leb128(&self.header[1..]) {
+            Some(size) => size,
+            // Let the parser report an overlong size.
+            None if self.header.len() > 11 => return self.forward_header(u64::MAX),
+            None => return Ok(()),
+        };
+        let contents = self.header.len() - 1 - len;
+
+        if self.header[0] != 0 {
+            return self.forward_header(size);
+        }
+
+        // Keep the name of custom sections, and whatever is malformed.
+        let name_end = read_leb128(&self.header[1 + len..])
+            .map(|(name_len, name_len_len)| name_len_len as u64 + name_len);
+        if contents as u64 >= size {
+            return self.forward_header(size - contents as u64);
+        }
+        match name_end {
+            Some(name_end) if contents as u64 >= name_end => {}
+            _ => return Ok(()),
+        }
+
+        let mut section = vec![0];
+        leb128::write::unsigned(&mut section, contents as u64)?;
+        section.extend_from_slice(&self.header[1 + len..]);
+        self.header.clear();
+        self.forward(&section)?;
+
+        let remaining = size - contents as u64;
+        self.skipped.push((self.parsed, self.offset + remaining));
+        self.state = State::Skip(remaining);
+        Ok(())
+    }
+
+    /// Passes the collected header through, followed by `size` bytes.
+    fn forward_header(&mut self, size: u64) -> Result<()> {
+        let header = std::mem::take(&mut self.header);
+        self.forward(&header)?;
+        self.state = State::Body(size);
+        Ok(())
+    }
+
+    fn forward(&mut self, data: &[u8]) -> Result<()> {
+        self.parsed += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        self.parse(false)
+    }
+
+    fn parse(&mut self, eof: bool) -> Result<()> {
+        loop {
+            let (consumed, payload) = match self.parser.parse(&self.buffer, eof) {
+                Ok(Chunk::NeedMoreData(_)) => return Ok(()),
+                Ok(Chunk::Parsed { consumed, payload }) => (consumed, payload),
+                Err(e) => return Err(self.error(e)),
+            };
+
+            let result = match self.validator.payload(&payload) {
+                Ok(ValidPayload::Ok) => Ok(()),
+                Ok(ValidPayload::Parser(parser)) => {
+                    let parent = std::mem::replace(&mut self.parser, parser);
+                    self.stack.push(parent);
+                    Ok(())
+                }
+                Ok(ValidPayload::Func(func, body)) => {
+                    func.into_validator(Default::default()).validate(&body)
+                }
+                Ok(ValidPayload::End(_)) => match self.stack.pop() {
+                    Some(parent) => {
+                        self.parser = parent;
+                        Ok(())
+                    }
+                    None => return Ok(()),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                return Err(self.error(e));
+            }
+
+            self.buffer.drain(..consumed);
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        match self.state {
+            State::Header if self.header.is_empty() => self.parse(true),
+            _ => Err(BundleError::Parse {
+                offset: self.offset,
+                message: "unexpected end of module".to_string(),
+            }),
+        }
+    }
+}
+
+/// Validates the module read from or written to `inner` as it goes.
+pub(crate) struct Validating<T> {
+    inner: T,
+    validator: Validator,
+    error: Option<BundleError>,
+}
+
+impl<T> Validating<T> {
+    pub(crate) fn new(inner: T, features: WasmFeatures) -> Self {
+        Self {
+            inner,
+            validator: Validator::new(features),
+            error: None,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        self.validator.update(data).map_err(|e| {
+            self.error = Some(e);
+            io::Error::new(ErrorKind::InvalidData, "invalid module")
+        })
+    }
+
+    /// Finishes validating the module, once everything has been read
+    /// or written with `result`.
+    ///
+    /// An error found while validating takes precedence, as it is
+    /// what stopped the stream.
+    pub(crate) fn finish(self, result: Result<()>) -> Result<()> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        result?;
+        self.validator.finish()
+    }
+}
+
+impl<T: Read> Read for Validating<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Validating<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::{write_section, EMPTY_MODULE};
+
+    fn validate(module: &[u8], chunk: usize) -> Result<()> {
+        let mut validator = Validator::new(WasmFeatures::default());
+        for chunk in module.chunks(chunk) {
+            validator.update(chunk)?;
+        }
+        validator.finish()
+    }
+
+    /// Checks that errors are reported at the offset in the original
+    /// module, whatever the chunks it is fed in.
+    fn check_offset(module: &[u8]) {
+        let expected = match wasmparser::Validator::new_with_features(WasmFeatures::default())
+            .validate_all(module)
+        {
+            Ok(_) => panic!("module is valid"),
+            Err(e) => e.offset(),
+        };
+
+        for chunk in &[1, 3, 100, module.len()] {
+            match validate(module, *chunk) {
+                Err(BundleError::Parse { offset, .. }) => assert_eq!(offset, expected),
+                result => panic!("unexpected {:?} in chunks of {}", result, chunk),
+            }
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let mut module = EMPTY_MODULE.to_vec();
+        write_section("skipped", &[0; 1000], &mut module).unwrap();
+        // A type section with a function type taking and returning i32.
+        module.extend_from_slice(&[1, 6, 1, 0x60, 1, 0x7f, 1, 0x7f]);
+        write_section("skipped", &[0; 10], &mut module).unwrap();
+
+        for chunk in &[1, 3, 100, module.len()] {
+            validate(&module, *chunk).unwrap();
+        }
+    }
+
+    #[test]
+    fn error_after_custom_sections() {
+        let mut module = EMPTY_MODULE.to_vec();
+        write_section("skipped", &[0; 1000], &mut module).unwrap();
+        write_section("also skipped", &[0; 200], &mut module).unwrap();
+        // A type section with an invalid form.
+        module.extend_from_slice(&[1, 3, 1, 0x42, 0]);
+        check_offset(&module);
+    }
+
+    #[test]
+    fn error_between_custom_sections() {
+        let mut module = EMPTY_MODULE.to_vec();
+        write_section("skipped", &[0; 300], &mut module).unwrap();
+        // A function section referring to a type that doesn't exist.
+        module.extend_from_slice(&[3, 2, 1, 5]);
+        write_section("skipped", &[0; 300], &mut module).unwrap();
+        check_offset(&module);
+    }
+
+    #[test]
+    fn error_in_custom_section_header() {
+        let mut module = EMPTY_MODULE.to_vec();
+        write_section("skipped", &[0; 300], &mut module).unwrap();
+        // A custom section whose name runs past its end.
+        module.extend_from_slice(&[0, 2, 5, b'a']);
+        check_offset(&module);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut module = EMPTY_MODULE.to_vec();
+        write_section("skipped", &[0; 300], &mut module).unwrap();
+        module.truncate(module.len() - 10);
+        assert!(matches!(
+            validate(&module, 7),
+            Err(BundleError::Parse { offset, .. }) if offset == module.len() as u64
+        ));
+    }
+}